@@ -1,7 +1,8 @@
 use std::str;
 use std::net::TcpStream;
-use std::io::{self,Write, prelude::*,BufReader}; 
-use mqtt_v5::{encoder, types::{Packet, ConnectPacket, PublishPacket, SubscribePacket, SubscriptionTopic, QoS, 
+use std::io::{self,Write, prelude::*}; 
+use std::thread;
+use mqtt_v5::{decoder, encoder, types::{Packet, ConnectPacket, PublishAckPacket, PublishAckReason, PublishPacket, SubscribePacket, SubscriptionTopic, QoS, 
     RetainHandling, ProtocolVersion}};
 use bytes::{Bytes, BytesMut};
 
@@ -11,7 +12,8 @@ fn send_connect(mut stream: &TcpStream) {
         protocol_name: String::from("cm_mqtt"),
         protocol_version: ProtocolVersion::V500,
         clean_start: true,
-        keep_alive: 1,
+        // this client never sends PINGREQ, so don't ask the broker to time it out
+        keep_alive: 0,
         user_properties: Vec::new(),
         client_id: String::from("1004"),
         session_expiry_interval: None,
@@ -46,7 +48,7 @@ fn send_sub(mut stream: &TcpStream, topic_name: String, packet_num: &u16) -> u16
         subscription_identifier: None,
         user_properties: Vec::new(),
         subscription_topics: vec![SubscriptionTopic {
-            topic_filter: v[1].parse().unwrap(),
+            topic_filter: v[1].trim().parse().unwrap(),
             maximum_qos: QoS::AtLeastOnce,
             no_local: false,
             retain_as_published: false,
@@ -106,20 +108,51 @@ fn send_pub(mut stream: &TcpStream, args: String, packet_num: &u16) ->u16 {
     p_num + 1
 }
 
+// print every packet the broker sends back
+fn read_responses(mut stream: TcpStream) {
+    let mut buf = [0; 4096];
+    let mut pending = BytesMut::new();
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => {
+                println!("\tBroker closed the connection");
+                return;
+            },
+            Ok(n) => pending.extend_from_slice(&buf[..n]),
+        }
+
+        while let Ok(Some(packet)) = decoder::decode_mqtt(&mut pending, ProtocolVersion::V500) {
+            println!("\tReceived {:?}", packet);
+
+            // acknowledge QoS 1 messages so the broker stops resending them
+            if let Packet::Publish(PublishPacket { qos: QoS::AtLeastOnce, packet_id: Some(packet_id), .. }) = packet {
+                let ack = Packet::PublishAck(PublishAckPacket {
+                    packet_id,
+                    reason_code: PublishAckReason::Success,
+                    reason_string: None,
+                    user_properties: Vec::new(),
+                });
+                let mut buf = BytesMut::new();
+                encoder::encode_mqtt(&ack, &mut buf, ProtocolVersion::V500);
+                stream.write_all(&buf).expect("failed to send puback packet");
+            }
+        }
+    }
+}
+
 fn main() -> io::Result<()>{
-    let mut packet_num = 00;
+    let mut packet_num = 1;
     // Struct used to start requests to the server.
-    let mut stream = TcpStream::connect("127.0.0.1:7878")?;             // Check TcpStream Connection to the server
-    for _ in 0..1000 {
+    let stream = TcpStream::connect("127.0.0.1:7878")?;             // Check TcpStream Connection to the server
+    let reader = stream.try_clone()?;
+    thread::spawn(move || read_responses(reader));
+
+    loop {
         let mut input = String::new();                                  // Allow sender to enter message input 
-        io::stdin().read_line(&mut input).expect("Failed to read");     // First access the input message and read it
-        stream.write(input.as_bytes()).expect("failed to write");       // Write the message so that the receiver can access it 
-
-        // Add buffering so that the receiver can read messages from the stream
-        let mut reader =BufReader::new(&stream);
-        let mut buffer: Vec<u8> = Vec::new();               // Check if this input message values are u8
-        reader.read_until(b'\n',&mut buffer)?;              // Read input information
-            
+        if io::stdin().read_line(&mut input)? == 0 {                    // First access the input message and read it
+            return Ok(());
+        }
+
         if input.contains("connect") {
             send_connect(&stream);
         }
@@ -135,5 +168,4 @@ fn main() -> io::Result<()>{
         }
         // println!("");
     }
-    Ok(())
-}
\ No newline at end of file
+}
@@ -1,29 +1,52 @@
-mod tree;
+pub mod acl;
+pub mod auth;
+pub mod retained;
+pub mod session;
+pub mod tree;
 // use tree::tree::SubscriptionTree;
+#[allow(clippy::module_inception)]
 pub mod broker {
     // use std::ops::Sub;
 
     // broker function
     use mqtt_v5::{
-        topic::TopicFilter,
+        topic::{Topic, TopicFilter},
         types::{
-        properties::AssignedClientIdentifier, //PublishPacket, PublishAckPacket
+        properties::{
+            AssignedClientIdentifier, AuthenticationData, MaximumPacketSize, MaximumQos, ServerKeepAlive, SharedSubscriptionAvailable, SubscriptionIdentifier,
+        },
         ConnectAckPacket,
         ConnectPacket,
-        ConnectReason, SubscribePacket, SubscribeAckPacket, SubscribeAckReason,
+        ConnectReason, DisconnectPacket, DisconnectReason, FinalWill, Packet, PublishAckPacket, PublishAckReason, PublishCompletePacket,
+        PublishCompleteReason, PublishPacket, PublishReceivedPacket, PublishReceivedReason,
+        PublishReleasePacket, QoS, RetainHandling,
+        SubscribePacket, SubscribeAckPacket, SubscribeAckReason,
+        UnsubscribeAckPacket, UnsubscribeAckReason, UnsubscribePacket,
         }
     };
 
+    use std::collections::HashMap;
+    use std::sync::mpsc::Sender;
+    use std::time::{Duration, Instant};
+
+    use super::acl::acl::{Access, Authorizer};
+    use super::auth::auth::{AuthExchange, AuthMechanism, Authenticator};
+    use bytes::Bytes;
+    use super::retained::retained::RetainedStore;
+    use super::session::session::Session;
     use super::tree::tree::SubscriptionTree;
 
     // global ds
     // 1-level subscriptions
     // specified type T (for subscriptions)
-    // client_id
-    #[derive(Debug)]
-    #[allow(dead_code)]
-    struct Subs {
-        client_id: String,
+    // one subscriber record per (client, filter) in the subscription tree
+    #[derive(Debug, Clone)]
+    pub struct Subs {
+        pub client_id: String,
+        pub qos: QoS, // granted QoS
+        pub no_local: bool,
+        pub retain_as_published: bool,
+        pub subscription_identifier: Option<SubscriptionIdentifier>,
         // active: bool,
     }
     // impl <T> Iterator for Subs<> where T: fmt::Display {
@@ -34,84 +57,507 @@ pub mod broker {
     //     }
     // }
 
+    // broker settings
+    #[derive(Debug, Clone)]
+    pub struct BrokerConfig {
+        // highest QoS granted to subscriptions and advertised in CONNACK
+        pub maximum_qos: QoS,
+        // keep-alive in seconds sent in CONNACK in place of the client's, None keeps the client's
+        pub server_keep_alive: Option<u16>,
+        // largest packet in bytes a client may send, advertised in CONNACK
+        pub maximum_packet_size: u32,
+        // how long a new connection has to get through CONNECT and any authentication exchange
+        pub connect_timeout: Duration,
+    }
+
+    impl Default for BrokerConfig {
+        fn default() -> Self {
+            Self {
+                maximum_qos: QoS::ExactlyOnce,
+                server_keep_alive: None,
+                maximum_packet_size: 1024 * 1024,
+                connect_timeout: Duration::from_secs(10),
+            }
+        }
+    }
+
     // clients (may be handled by session?)
     pub struct MBroker {
-        clients: Vec<Subs>,
+        config: BrokerConfig,
+        sessions: HashMap<String, Session>, // client state by client id
         subscriptions: SubscriptionTree<Subs>, // use as subscriptions list
-        unsub_list: Vec<TopicFilter>,   // use for unsubscriptions list
+        retained: RetainedStore, // last retained message per topic
+        next_connection_id: u64, // tells a client's connections apart
+        next_assigned_id: u64, // for clients that connect without an id
+        authenticator: Option<Box<dyn Authenticator>>, // checks CONNECT credentials, None lets everyone in
+        authorizer: Option<Box<dyn Authorizer>>, // checks topic access, None allows everything
+        auth_mechanisms: Vec<Box<dyn AuthMechanism>>, // enhanced authentication methods
     }
     impl MBroker {
-        #[allow(dead_code)]
         pub fn new() -> Self {
+            Self::with_config(BrokerConfig::default())
+        }
+
+        pub fn with_config(config: BrokerConfig) -> Self {
             Self {
+                config,
                 subscriptions: SubscriptionTree::new(),
-                unsub_list: Vec::new(),
-                clients: Vec::new()
+                retained: RetainedStore::new(),
+                next_connection_id: 1,
+                next_assigned_id: 1,
+                sessions: HashMap::new(),
+                authenticator: None,
+                authorizer: None,
+                auth_mechanisms: Vec::new(),
+            }
+        }
+
+        // require clients to pass the authenticator before they connect
+        pub fn set_authenticator(&mut self, authenticator: Box<dyn Authenticator>) {
+            self.authenticator = Some(authenticator);
+        }
+
+        // let clients authenticate with the mechanism's method
+        pub fn add_auth_mechanism(&mut self, mechanism: Box<dyn AuthMechanism>) {
+            self.auth_mechanisms.push(mechanism);
+        }
+
+        // check every subscribe, publish and delivery against the authorizer
+        pub fn set_authorizer(&mut self, authorizer: Box<dyn Authorizer>) {
+            self.authorizer = Some(authorizer);
+        }
+
+        // largest packet in bytes a connection should accept from its client
+        pub fn maximum_packet_size(&self) -> u32 {
+            self.config.maximum_packet_size
+        }
+
+        pub fn connect_timeout(&self) -> Duration {
+            self.config.connect_timeout
+        }
+
+        // whether client_id may read or write messages on the topic
+        fn is_authorized(&self, client_id: &str, access: Access, topic: &Topic) -> bool {
+            let user_name = self.sessions.get(client_id).and_then(|session| session.user_name());
+            authorized(&self.authorizer, client_id, user_name, access, topic)
+        }
+
+        // route messages for client_id to its connection
+        // unacknowledged messages from an earlier connection are sent again
+        // returns an id for the connection to hand back to remove_outbound
+        pub fn register_outbound(&mut self, client_id: &str, outbound: Sender<Packet>) -> u64 {
+            let connection_id = self.next_connection_id;
+            self.next_connection_id += 1;
+
+            self.sessions
+                .entry(client_id.to_string())
+                .or_insert_with(Session::new)
+                .attach(connection_id, outbound);
+            connection_id
+        }
+
+        // client_id's connection went away
+        // the session stays until its expiry interval runs out
+        pub fn remove_outbound(&mut self, client_id: &str, connection_id: u64) {
+            if let Some(session) = self.sessions.get_mut(client_id) {
+                // a connection that was taken over must not detach its replacement
+                if session.connection_id() == Some(connection_id) {
+                    session.detach();
+                }
             }
+
+            // a will without a delay goes out straight away
+            self.publish_due_wills(Instant::now());
         }
-        #[allow(dead_code)]
+
+        // the client sent DISCONNECT
+        // returns the reason to answer with if the DISCONNECT itself breaks the protocol
+        pub fn accept_disconnect(&mut self, client_id: &str, disconnect_packet: DisconnectPacket) -> Result<(), DisconnectReason> {
+            let session = match self.sessions.get_mut(client_id) {
+                Some(session) => session,
+                None => return Ok(()),
+            };
+
+            // the client may change the session expiry, but can't bring back
+            // a session it said would end with the connection
+            if let Some(expiry) = disconnect_packet.session_expiry_interval {
+                if session.expiry_interval() == 0 && expiry.0 != 0 {
+                    return Err(DisconnectReason::ProtocolError);
+                }
+                session.set_expiry_interval(expiry.0);
+            }
+
+            // the will is only sent on a normal disconnect if the client asks for it
+            if disconnect_packet.reason_code != DisconnectReason::DisconnectWithWillMessage {
+                session.clear_will();
+            }
+            Ok(())
+        }
+
+        // tell every connected client the broker is going away
+        // each connection closes once it has written out what was queued for it
+        pub fn shutdown(&mut self) {
+            for session in self.sessions.values_mut() {
+                if session.is_connected() {
+                    session.close(DisconnectReason::ServerShuttingDown);
+                }
+            }
+        }
+
+        // whether any client still has a connection open
+        pub fn has_connections(&self) -> bool {
+            self.sessions.values().any(|session| session.is_connected())
+        }
+
+        // publish every will whose delay has passed
+        pub fn publish_due_wills(&mut self, now: Instant) {
+            let wills: Vec<(String, PublishPacket)> = self
+                .sessions
+                .iter_mut()
+                .filter_map(|(client_id, session)| Some((client_id.clone(), session.take_due_will(now)?)))
+                .collect();
+
+            for (client_id, will) in wills {
+                self.publish_will(&client_id, will);
+            }
+        }
+
+        // route a will like any other publish from its client
+        fn publish_will(&mut self, client_id: &str, will: PublishPacket) {
+            let (deliveries, _) = self.accept_publish(client_id, will);
+            self.deliver(deliveries);
+        }
+
+        // drop every disconnected session whose expiry interval has passed
+        pub fn expire_sessions(&mut self, now: Instant) {
+            let expired: Vec<String> = self
+                .sessions
+                .iter()
+                .filter(|(_, session)| session.is_expired(now))
+                .map(|(client_id, _)| client_id.clone())
+                .collect();
+
+            for client_id in expired {
+                self.discard_session(&client_id);
+            }
+        }
+
+        // end client_id's session along with its subscriptions
+        fn discard_session(&mut self, client_id: &str) {
+            // a will still waiting on its delay goes out when the session ends,
+            // while the session can still tell the ACLs who published it
+            let will = self.sessions.get_mut(client_id).and_then(|session| session.take_will());
+            if let Some(will) = will {
+                self.publish_will(client_id, will);
+            }
+
+            if let Some(mut session) = self.sessions.remove(client_id) {
+                for (filter, token) in session.take_subscriptions() {
+                    self.subscriptions.remove(&filter, token);
+                }
+            }
+        }
+
+        // hand each message to its client's session
+        // QoS 0 messages for clients that aren't connected are dropped,
+        // QoS 1 messages wait in the session until the client is back
+        pub fn deliver(&mut self, deliveries: Vec<(String, PublishPacket)>) {
+            for (client_id, packet) in deliveries {
+                if let Some(session) = self.sessions.get_mut(&client_id) {
+                    session.publish(packet);
+                }
+            }
+        }
+
+        // receive puback packet for a message the broker sent to client_id
+        pub fn accept_puback(&mut self, client_id: &str, pub_ack: PublishAckPacket) {
+            if let Some(session) = self.sessions.get_mut(client_id) {
+                session.acknowledge(pub_ack.packet_id);
+            }
+        }
+
+        // receive pubrec packet for a QoS 2 message the broker sent to client_id
+        // the session answers with a PUBREL
+        pub fn accept_pubrec(&mut self, client_id: &str, pub_rec: PublishReceivedPacket) {
+            if let Some(session) = self.sessions.get_mut(client_id) {
+                // a failure reason ends the exchange, there's nothing to release
+                if pub_rec.reason_code as u8 >= 0x80 {
+                    session.acknowledge(pub_rec.packet_id);
+                } else {
+                    session.release(pub_rec.packet_id);
+                }
+            }
+        }
+
+        // receive pubcomp packet, the end of a QoS 2 exchange with client_id
+        pub fn accept_pubcomp(&mut self, client_id: &str, pub_comp: PublishCompletePacket) {
+            if let Some(session) = self.sessions.get_mut(client_id) {
+                session.acknowledge(pub_comp.packet_id);
+            }
+        }
+
+        // begin an enhanced authentication exchange, None if the broker doesn't know the method
+        pub fn start_auth(&self, method: &str) -> Option<Box<dyn AuthExchange>> {
+            self.auth_mechanisms.iter().find(|m| m.name() == method).map(|m| m.start())
+        }
+
         // receive connect packet
         pub fn accept_new_client(&mut self, connect_packet: ConnectPacket) -> ConnectAckPacket {
+            // enhanced authentication has to finish its exchange first, see accept_authenticated_client
+            if connect_packet.authentication_method.is_some() {
+                return connect_ack(ConnectReason::BadAuthenticationMethod);
+            }
+
+            // check credentials before touching any session state
+            if let Some(authenticator) = &self.authenticator {
+                let reason = authenticator.authenticate(
+                    &connect_packet.client_id,
+                    connect_packet.user_name.as_deref(),
+                    connect_packet.password.as_deref(),
+                );
+                if reason != ConnectReason::Success {
+                    return connect_ack(reason);
+                }
+            }
+
+            let user_name = connect_packet.user_name.clone();
+            self.connect_client(connect_packet, user_name)
+        }
+
+        // receive connect packet from a client already proven to be user_name,
+        // by an enhanced authentication exchange or a TLS client certificate
+        // data is the exchange's last word, sent back in the CONNACK
+        pub fn accept_authenticated_client(
+            &mut self,
+            connect_packet: ConnectPacket,
+            user_name: String,
+            data: Option<Vec<u8>>,
+        ) -> ConnectAckPacket {
+            let authentication_method = connect_packet.authentication_method.clone();
+            let mut conn_ack = self.connect_client(connect_packet, Some(user_name));
+
+            if conn_ack.reason_code == ConnectReason::Success {
+                conn_ack.authentication_method = authentication_method;
+                conn_ack.authentication_data = data.map(|data| AuthenticationData(Bytes::from(data)));
+            }
+            conn_ack
+        }
+
+        // set up the session for an authenticated CONNECT
+        fn connect_client(&mut self, connect_packet: ConnectPacket, user_name: Option<String>) -> ConnectAckPacket {
             // create connect_ack packet
-            let conn_ack = ConnectAckPacket {
-                session_present: true,
-                reason_code: ConnectReason::Success,
-                session_expiry_interval: None,
-                receive_maximum: None,
-                maximum_qos: None,
-                retain_available: None,
-                maximum_packet_size: None,
-                assigned_client_identifier: Some(AssignedClientIdentifier(
-                    connect_packet.client_id.clone(),
-                )),
-                topic_alias_maximum: None,
-                reason_string: None,
-                response_information: None,
-                user_properties: vec![],
-                wildcard_subscription_available: None,
-                subscription_identifiers_available: None,
-                shared_subscription_available: None,
-                server_keep_alive: None,
-                server_reference: None,
-                authentication_method: None,
-                authentication_data: None,
+            let mut conn_ack = ConnectAckPacket {
+                // only advertised when the broker can't do QoS 2
+                maximum_qos: match self.config.maximum_qos {
+                    QoS::ExactlyOnce => None,
+                    qos => Some(MaximumQos(qos)),
+                },
+                shared_subscription_available: Some(SharedSubscriptionAvailable(0)),
+                server_keep_alive: self.config.server_keep_alive.map(ServerKeepAlive),
+                maximum_packet_size: Some(MaximumPacketSize(self.config.maximum_packet_size)),
+                ..connect_ack(ConnectReason::Success)
             };
 
-            // add client id to client ds
-            self.clients.push(Subs {
-                client_id: connect_packet.client_id,
-            }); // , active: true
+            // an empty client id asks the broker to pick one,
+            // which only works for a new session since there's nothing to resume
+            let client_id = if connect_packet.client_id.is_empty() {
+                if !connect_packet.clean_start {
+                    conn_ack.reason_code = ConnectReason::ClientIdentifierNotValid;
+                    return conn_ack;
+                }
+                let client_id = self.assign_client_id();
+                conn_ack.assigned_client_identifier = Some(AssignedClientIdentifier(client_id.clone()));
+                client_id
+            } else {
+                connect_packet.client_id.clone()
+            };
+
+            // the will has to be something the broker could publish
+            let will = match connect_packet.will.as_ref().map(will_packet) {
+                Some(Ok(will)) if (will.qos as u8) > (self.config.maximum_qos as u8) => {
+                    conn_ack.reason_code = ConnectReason::QosNotSupported;
+                    return conn_ack;
+                },
+                Some(Ok(will)) => Some(will),
+                Some(Err(reason)) => {
+                    conn_ack.reason_code = reason;
+                    return conn_ack;
+                },
+                None => None,
+            };
+            let will_delay = connect_packet
+                .will
+                .as_ref()
+                .and_then(|w| w.will_delay_interval.as_ref())
+                .map(|d| d.0)
+                .unwrap_or(0);
+
+            // a session that ran out while the client was away can't be resumed
+            self.expire_sessions(Instant::now());
+
+            // a connection already using this client id is disconnected,
+            // the new one gets the session
+            if let Some(session) = self.sessions.get_mut(&client_id) {
+                if session.is_connected() {
+                    session.take_over();
+                }
+            }
+            // the old connection's will, unless it has a delay the new connection cancels below
+            self.publish_due_wills(Instant::now());
+
+            // clean start throws away whatever the client left behind
+            if connect_packet.clean_start {
+                self.discard_session(&client_id);
+            }
+            conn_ack.session_present = self.sessions.contains_key(&client_id);
+
+            // add client id to client ds, or pick up the existing session
+            let session = self.sessions.entry(client_id).or_insert_with(Session::new);
+            session.set_receive_maximum(
+                connect_packet.receive_maximum.map(|r| r.0).unwrap_or(u16::MAX),
+            );
+            // an absent interval means the session ends with the connection
+            session.set_expiry_interval(
+                connect_packet.session_expiry_interval.map(|s| s.0).unwrap_or(0),
+            );
+            // reconnecting in time also cancels the previous connection's will
+            session.set_will(will, will_delay);
+            session.set_user_name(user_name);
 
             // send the client the ack
             conn_ack
         }
 
-        #[allow(dead_code)]
-        // receive subscribe packet
-        pub fn accept_sub(&mut self, sub_packet: SubscribePacket) -> SubscribeAckPacket {
+        // a client id no session is using, for a client that didn't send one
+        fn assign_client_id(&mut self) -> String {
+            loop {
+                let client_id = format!("mbroker-{}", self.next_assigned_id);
+                self.next_assigned_id += 1;
+
+                if !self.sessions.contains_key(&client_id) {
+                    return client_id;
+                }
+            }
+        }
+
+        // receive subscribe packet from client_id
+        pub fn accept_sub(&mut self, client_id: &str, sub_packet: SubscribePacket) -> SubscribeAckPacket {
+            let session = self.sessions.entry(client_id.to_string()).or_insert_with(Session::new);
+            let user_name = session.user_name().map(str::to_string);
+            let mut reason_codes = Vec::with_capacity(sub_packet.subscription_topics.len());
+            let mut retained = Vec::new();
 
             // add to subscription tree
             for topic in &sub_packet.subscription_topics {
-                let subscription = Subs{client_id: sub_packet.packet_id.clone().to_string()};
+                // shared subscriptions aren't supported by the tree yet
+                if let TopicFilter::SharedConcrete { .. } | TopicFilter::SharedWildcard { .. } = topic.topic_filter {
+                    reason_codes.push(SubscribeAckReason::SharedSubscriptionsNotSupported);
+                    continue;
+                }
+
+                if let Some(authorizer) = &self.authorizer {
+                    if !authorizer.can_subscribe(client_id, user_name.as_deref(), &topic.topic_filter) {
+                        reason_codes.push(SubscribeAckReason::NotAuthorized);
+                        continue;
+                    }
+                }
 
-                // store in unsubscriptions list
-                self.unsub_list.push(topic.topic_filter.clone());
+                // grant at most the broker's maximum QoS
+                let granted_qos = min_qos(topic.maximum_qos, self.config.maximum_qos);
+                let subscription = Subs {
+                    client_id: client_id.to_string(),
+                    qos: granted_qos,
+                    no_local: topic.no_local,
+                    retain_as_published: topic.retain_as_published,
+                    subscription_identifier: sub_packet.subscription_identifier.clone(),
+                };
 
                 // store in subscriptions list
-                println!("SubTree count: \t{}",self.subscriptions.insert(&topic.topic_filter, subscription));
-                // let match_subs = self.subscriptions.matching_subscribers(topic);
-                // println!("Matching Subscribers: \t{:?}" , self.subscriptions.matching_subscribers(topic.parse().unwrap()));
+                let token = self.subscriptions.insert(&topic.topic_filter, subscription);
+
+                // keep the token in the session so the subscription can be removed on unsubscribe,
+                // subscribing to the same filter again replaces the old subscription
+                let existing = session.add_subscription(topic.topic_filter.clone(), token);
+                if let Some(old_token) = existing {
+                    self.subscriptions.remove(&topic.topic_filter, old_token);
+                }
+
+                reason_codes.push(match granted_qos {
+                    QoS::AtMostOnce => SubscribeAckReason::GrantedQoSZero,
+                    QoS::AtLeastOnce => SubscribeAckReason::GrantedQoSOne,
+                    QoS::ExactlyOnce => SubscribeAckReason::GrantedQoSTwo,
+                });
+
+                // send the retained messages this filter matches, if the client asked for them
+                let send_retained = match topic.retain_handling {
+                    RetainHandling::SendAtSubscribeTime => true,
+                    RetainHandling::SendAtSubscribeTimeIfNonexistent => existing.is_none(),
+                    RetainHandling::DoNotSend => false,
+                };
+                if !send_retained {
+                    continue;
+                }
+
+                // the filter may reach further than what the client may read
+                let readable = self
+                    .retained
+                    .matching(&topic.topic_filter)
+                    .into_iter()
+                    .filter(|packet| authorized(&self.authorizer, client_id, user_name.as_deref(), Access::Read, &packet.topic));
+                retained.extend(readable.map(|packet| {
+                    let outgoing = PublishPacket {
+                        is_duplicate: false,
+                        qos: min_qos(packet.qos, granted_qos),
+                        retain: true,
+                        packet_id: None,
+                        subscription_identifier: sub_packet.subscription_identifier.clone(),
+                        topic_alias: None,
+                        ..packet.clone()
+                    };
+                    (client_id.to_string(), outgoing)
+                }));
+
             }
 
-            let sub_ack = SubscribeAckPacket {
-                packet_id: sub_packet.packet_id.clone(),
-                reason_codes: vec![SubscribeAckReason::GrantedQoSZero],
+            // queued behind the SUBACK on the client's connection
+            self.deliver(retained);
+
+            // one reason code per requested filter, in the same order
+            SubscribeAckPacket {
+                packet_id: sub_packet.packet_id,
+                reason_codes,
                 reason_string: None,
                 user_properties:  Vec::new(),
-            };
+            }
+        }
+
+        // receive unsubscribe packet from client_id
+        pub fn accept_unsub(&mut self, client_id: &str, unsub_packet: UnsubscribePacket) -> UnsubscribeAckPacket {
+            let mut session = self.sessions.get_mut(client_id);
 
-            sub_ack
+            // one reason code per filter, in the order they were sent
+            let reason_codes = unsub_packet
+                .topic_filters
+                .iter()
+                .map(|filter| {
+                    match session.as_mut().and_then(|s| s.remove_subscription(filter)) {
+                        Some(token) => {
+                            self.subscriptions.remove(filter, token);
+                            UnsubscribeAckReason::Success
+                        },
+                        None => UnsubscribeAckReason::NoSubscriptionExisted,
+                    }
+                })
+                .collect();
+
+            UnsubscribeAckPacket {
+                packet_id: unsub_packet.packet_id,
+                reason_string: None,
+                user_properties: Vec::new(),
+                reason_codes,
+            }
         }
+
         // loop through subscription_topics attribute
         // add to subscription tree of specified type T
         // collect the QoS
@@ -119,12 +565,200 @@ pub mod broker {
         // add the collected QoS
         // send the client the ack
 
-        // receive publish packet
-        // resources : publish_message
-        // input : publish packet
-        // pub fn accept_publish_packet (&mut self, pub_packet: PublishPacket) -> PublishAckPacket {
+        // receive publish packet from client_id
+        // returns the (client id, packet) pairs to deliver and the ack for the publisher,
+        // or a DISCONNECT for a publish that breaks what CONNACK told the client
+        pub fn accept_publish(&mut self, client_id: &str, pub_packet: PublishPacket) -> (Vec<(String, PublishPacket)>, Option<Packet>) {
+            if (pub_packet.qos as u8) > (self.config.maximum_qos as u8) {
+                return (Vec::new(), Some(Packet::Disconnect(disconnect_packet(DisconnectReason::QosNotSupported))));
+            }
+            // CONNACK doesn't offer a topic alias maximum, so no alias is valid
+            if pub_packet.topic_alias.is_some() {
+                return (Vec::new(), Some(Packet::Disconnect(disconnect_packet(DisconnectReason::TopicAliasInvalid))));
+            }
+
+            // refused publishes are acknowledged but go nowhere, not even the retained store
+            if !self.is_authorized(client_id, Access::Write, &pub_packet.topic) {
+                let pub_ack = publish_ack(&pub_packet, PublishAckReason::NotAuthorized, PublishReceivedReason::NotAuthorized);
+                return (Vec::new(), pub_ack);
+            }
+
+            // a QoS 2 packet id that hasn't been released yet means the client resent
+            // the message; acknowledge it again but don't deliver it twice
+            if let (QoS::ExactlyOnce, Some(packet_id)) = (pub_packet.qos, pub_packet.packet_id) {
+                let is_new = match self.sessions.get_mut(client_id) {
+                    Some(session) => session.receive(packet_id),
+                    None => true,
+                };
+
+                if !is_new {
+                    return (Vec::new(), Some(Packet::PublishReceived(PublishReceivedPacket {
+                        packet_id,
+                        reason_code: PublishReceivedReason::Success,
+                        reason_string: None,
+                        user_properties: Vec::new(),
+                    })));
+                }
+            }
+
+            // keep the latest retained message for new subscribers
+            if pub_packet.retain {
+                self.retained.insert(pub_packet.clone());
+            }
+
+            // find every subscriber whose filter matches the topic
+            let deliveries: Vec<(String, PublishPacket)> = self
+                .subscriptions
+                .matching_subscribers(&pub_packet.topic)
+                // no_local subscribers don't get their own messages back
+                .filter(|sub| !(sub.no_local && sub.client_id == client_id))
+                // a wide filter doesn't let a subscriber read topics it isn't allowed to
+                .filter(|sub| self.is_authorized(&sub.client_id, Access::Read, &pub_packet.topic))
+                .map(|sub| {
+                    // forwarded copies are sent fresh, without the publisher's packet id;
+                    // the subscriber's session assigns its own
+                    let outgoing = PublishPacket {
+                        is_duplicate: false,
+                        qos: min_qos(pub_packet.qos, sub.qos),
+                        // RETAIN is cleared on forwarded messages unless the subscriber asked to keep it
+                        retain: pub_packet.retain && sub.retain_as_published,
+                        packet_id: None,
+                        subscription_identifier: sub.subscription_identifier.clone(),
+                        // aliases belong to one connection, and no subscriber agreed to any
+                        topic_alias: None,
+                        ..pub_packet.clone()
+                    };
+                    (sub.client_id.clone(), outgoing)
+                })
+                .collect();
 
-        // }
+            let pub_ack = if deliveries.is_empty() {
+                publish_ack(&pub_packet, PublishAckReason::NoMatchingSubscribers, PublishReceivedReason::NoMatchingSubscribers)
+            } else {
+                publish_ack(&pub_packet, PublishAckReason::Success, PublishReceivedReason::Success)
+            };
+
+            (deliveries, pub_ack)
+        }
+
+        // receive pubrel packet for a QoS 2 message client_id sent
+        pub fn accept_pubrel(&mut self, client_id: &str, pub_rel: PublishReleasePacket) -> PublishCompletePacket {
+            let released = self
+                .sessions
+                .get_mut(client_id)
+                .map(|session| session.complete(pub_rel.packet_id))
+                .unwrap_or(false);
+
+            PublishCompletePacket {
+                packet_id: pub_rel.packet_id,
+                reason_code: if released {
+                    PublishCompleteReason::Success
+                } else {
+                    PublishCompleteReason::PacketIdentifierNotFound
+                },
+                reason_string: None,
+                user_properties: Vec::new(),
+            }
+        }
+    }
 
+    // QoS 1 publishes are acknowledged with a PUBACK, QoS 2 with a PUBREC
+    fn publish_ack(pub_packet: &PublishPacket, ack_reason: PublishAckReason, received_reason: PublishReceivedReason) -> Option<Packet> {
+        match (pub_packet.qos, pub_packet.packet_id) {
+            (QoS::AtLeastOnce, Some(packet_id)) => Some(Packet::PublishAck(PublishAckPacket {
+                packet_id,
+                reason_code: ack_reason,
+                reason_string: None,
+                user_properties: Vec::new(),
+            })),
+            (QoS::ExactlyOnce, Some(packet_id)) => Some(Packet::PublishReceived(PublishReceivedPacket {
+                packet_id,
+                reason_code: received_reason,
+                reason_string: None,
+                user_properties: Vec::new(),
+            })),
+            _ => None,
+        }
+    }
+
+    // whether the authorizer lets the client read or write the topic, everything is allowed without one
+    fn authorized(
+        authorizer: &Option<Box<dyn Authorizer>>,
+        client_id: &str,
+        user_name: Option<&str>,
+        access: Access,
+        topic: &Topic,
+    ) -> bool {
+        match authorizer {
+            Some(authorizer) => authorizer.can_access(client_id, user_name, access, topic),
+            None => true,
+        }
+    }
+
+    // CONNACK with just a reason code
+    pub fn connect_ack(reason_code: ConnectReason) -> ConnectAckPacket {
+        ConnectAckPacket {
+            session_present: false,
+            reason_code,
+            session_expiry_interval: None,
+            receive_maximum: None,
+            maximum_qos: None,
+            retain_available: None,
+            maximum_packet_size: None,
+            assigned_client_identifier: None,
+            topic_alias_maximum: None,
+            reason_string: None,
+            response_information: None,
+            user_properties: vec![],
+            wildcard_subscription_available: None,
+            subscription_identifiers_available: None,
+            shared_subscription_available: None,
+            server_keep_alive: None,
+            server_reference: None,
+            authentication_method: None,
+            authentication_data: None,
+        }
+    }
+
+    // DISCONNECT sent by the broker before it closes a connection
+    pub fn disconnect_packet(reason_code: DisconnectReason) -> DisconnectPacket {
+        DisconnectPacket {
+            reason_code,
+            session_expiry_interval: None,
+            reason_string: None,
+            user_properties: Vec::new(),
+            server_reference: None,
+        }
+    }
+
+    // the PUBLISH a CONNECT's will turns into
+    fn will_packet(will: &FinalWill) -> Result<PublishPacket, ConnectReason> {
+        let topic: Topic = will.topic.parse().map_err(|_| ConnectReason::TopicNameInvalid)?;
+
+        Ok(PublishPacket {
+            is_duplicate: false,
+            qos: will.qos,
+            retain: will.should_retain,
+            topic,
+            packet_id: None,
+            payload_format_indicator: will.payload_format_indicator.clone(),
+            message_expiry_interval: will.message_expiry_interval.clone(),
+            topic_alias: None,
+            response_topic: will.response_topic.clone(),
+            correlation_data: will.correlation_data.clone(),
+            user_properties: will.user_properties.clone(),
+            subscription_identifier: None,
+            content_type: will.content_type.clone(),
+            payload: will.payload.clone(),
+        })
+    }
+
+    // the lower of two QoS levels
+    fn min_qos(a: QoS, b: QoS) -> QoS {
+        if (a as u8) <= (b as u8) {
+            a
+        } else {
+            b
+        }
     }
 }
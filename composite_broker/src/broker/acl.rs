@@ -0,0 +1,192 @@
+#[allow(clippy::module_inception)]
+pub mod acl {
+    use mqtt_v5::topic::{Topic, TopicFilter, TopicLevel};
+    use std::fs;
+    use std::io;
+    use std::path::Path;
+
+    use crate::broker::retained::retained::topic_matches;
+
+    // What a client wants to do with a topic
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Access {
+        // subscribe to it, or have its messages delivered
+        Read,
+        // publish to it
+        Write,
+    }
+
+    // Decides which topics a client may read and write
+    pub trait Authorizer: Send {
+        // whether the client may subscribe to the filter
+        fn can_subscribe(&self, client_id: &str, user_name: Option<&str>, filter: &TopicFilter) -> bool;
+        // whether the client may read or write messages on the topic
+        fn can_access(&self, client_id: &str, user_name: Option<&str>, access: Access, topic: &Topic) -> bool;
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Permission {
+        Allow,
+        Deny,
+    }
+
+    // Who a rule is about
+    #[derive(Debug, Clone, PartialEq)]
+    enum Subject {
+        All,
+        User(String),
+        Client(String),
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Rule {
+        permission: Permission,
+        read: bool,
+        write: bool,
+        subject: Subject,
+        // topic filter, %c and %u are replaced by the client id and user name
+        pattern: String,
+    }
+
+    impl Rule {
+        fn applies_to(&self, client_id: &str, user_name: Option<&str>, access: Access) -> bool {
+            let subject = match &self.subject {
+                Subject::All => true,
+                Subject::User(user) => user_name == Some(user.as_str()),
+                Subject::Client(client) => client == client_id,
+            };
+            let access = match access {
+                Access::Read => self.read,
+                Access::Write => self.write,
+            };
+            subject && access
+        }
+
+        // the pattern for this client, None if it names something the client doesn't have
+        fn filter_for(&self, client_id: &str, user_name: Option<&str>) -> Option<TopicFilter> {
+            let mut pattern = self.pattern.clone();
+            if pattern.contains("%c") {
+                pattern = pattern.replace("%c", substitution(client_id)?);
+            }
+            if pattern.contains("%u") {
+                pattern = pattern.replace("%u", substitution(user_name?)?);
+            }
+            pattern.parse().ok()
+        }
+    }
+
+    // Rules read from a file, one per line:
+    //   <allow|deny> <read|write|readwrite> <all|user NAME|client ID> <pattern>
+    // the first rule that matches decides, anything no rule matches is denied
+    #[derive(Debug, Default)]
+    pub struct AclFile {
+        rules: Vec<Rule>,
+    }
+
+    impl AclFile {
+        pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+            Self::parse(&fs::read_to_string(path)?)
+        }
+
+        pub fn parse(contents: &str) -> io::Result<Self> {
+            let mut rules = Vec::new();
+
+            for (number, line) in contents.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+
+                let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Bad ACL rule on line {}", number + 1));
+                let fields: Vec<&str> = line.split_whitespace().collect();
+
+                let permission = match fields.first() {
+                    Some(&"allow") => Permission::Allow,
+                    Some(&"deny") => Permission::Deny,
+                    _ => return Err(invalid()),
+                };
+                let (read, write) = match fields.get(1) {
+                    Some(&"read") => (true, false),
+                    Some(&"write") => (false, true),
+                    Some(&"readwrite") => (true, true),
+                    _ => return Err(invalid()),
+                };
+                let (subject, pattern) = match fields[2..] {
+                    ["all", pattern] => (Subject::All, pattern),
+                    ["user", user, pattern] => (Subject::User(user.to_string()), pattern),
+                    ["client", client, pattern] => (Subject::Client(client.to_string()), pattern),
+                    _ => return Err(invalid()),
+                };
+
+                // check the pattern is a filter with stand-in values for the substitutions
+                if pattern.replace("%c", "c").replace("%u", "u").parse::<TopicFilter>().is_err() {
+                    return Err(invalid());
+                }
+
+                rules.push(Rule { permission, read, write, subject, pattern: pattern.to_string() });
+            }
+
+            Ok(Self { rules })
+        }
+
+        fn decide(
+            &self,
+            client_id: &str,
+            user_name: Option<&str>,
+            access: Access,
+            matches: impl Fn(&TopicFilter) -> bool,
+        ) -> bool {
+            self.rules
+                .iter()
+                .filter(|rule| rule.applies_to(client_id, user_name, access))
+                .find(|rule| rule.filter_for(client_id, user_name).is_some_and(|pattern| matches(&pattern)))
+                .is_some_and(|rule| rule.permission == Permission::Allow)
+        }
+    }
+
+    impl Authorizer for AclFile {
+        fn can_subscribe(&self, client_id: &str, user_name: Option<&str>, filter: &TopicFilter) -> bool {
+            self.decide(client_id, user_name, Access::Read, |pattern| filter_covers(pattern, filter))
+        }
+
+        fn can_access(&self, client_id: &str, user_name: Option<&str>, access: Access, topic: &Topic) -> bool {
+            self.decide(client_id, user_name, access, |pattern| topic_matches(pattern, topic))
+        }
+    }
+
+    // a client id or user name can only stand in for a whole level,
+    // anything that would add levels or wildcards to the pattern doesn't match
+    fn substitution(value: &str) -> Option<&str> {
+        if value.is_empty() || value.contains(['/', '+', '#']) {
+            None
+        } else {
+            Some(value)
+        }
+    }
+
+    // whether every topic the filter matches is also matched by the pattern
+    pub fn filter_covers(pattern: &TopicFilter, filter: &TopicFilter) -> bool {
+        let mut pattern_levels = pattern.levels();
+        let mut filter_levels = filter.levels();
+
+        // wildcards don't reach topics with leading dollar signs
+        if let (Some(TopicLevel::SingleLevelWildcard) | Some(TopicLevel::MultiLevelWildcard), Some(TopicLevel::Concrete(f))) =
+            (pattern.levels().next(), filter.levels().next())
+        {
+            if f.starts_with('$') {
+                return false;
+            }
+        }
+
+        loop {
+            match (pattern_levels.next(), filter_levels.next()) {
+                (Some(TopicLevel::MultiLevelWildcard), _) => return true,
+                (Some(TopicLevel::SingleLevelWildcard), Some(TopicLevel::Concrete(_)))
+                | (Some(TopicLevel::SingleLevelWildcard), Some(TopicLevel::SingleLevelWildcard)) => {},
+                (Some(TopicLevel::Concrete(p)), Some(TopicLevel::Concrete(f))) if p == f => {},
+                (None, None) => return true,
+                _ => return false,
+            }
+        }
+    }
+}
@@ -0,0 +1,348 @@
+#[allow(clippy::module_inception)]
+pub mod auth {
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
+    use hmac::{Hmac, Mac};
+    use mqtt_v5::types::ConnectReason;
+    use rand::RngCore;
+    use sha2::{Digest, Sha256};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    // PBKDF2 rounds for passwords added with the passwd command
+    pub const DEFAULT_ITERATIONS: u32 = 10_000;
+
+    // Decides whether a CONNECT's credentials let the client in
+    // returns Success, or the reason code to refuse the CONNECT with
+    pub trait Authenticator: Send {
+        fn authenticate(&self, client_id: &str, user_name: Option<&str>, password: Option<&str>) -> ConnectReason;
+    }
+
+    // lets one password file back both CONNECT passwords and SCRAM
+    impl<A: Authenticator + Sync> Authenticator for Arc<A> {
+        fn authenticate(&self, client_id: &str, user_name: Option<&str>, password: Option<&str>) -> ConnectReason {
+            (**self).authenticate(client_id, user_name, password)
+        }
+    }
+
+    // An enhanced authentication method a client can name in CONNECT
+    pub trait AuthMechanism: Send {
+        // the authentication method property, like SCRAM-SHA-256
+        fn name(&self) -> &str;
+        // begin an exchange for a CONNECT or a re-authentication
+        fn start(&self) -> Box<dyn AuthExchange>;
+    }
+
+    // One run of a challenge/response exchange
+    pub trait AuthExchange: Send {
+        // handle the client's authentication data, from CONNECT or AUTH
+        fn step(&mut self, data: &[u8]) -> AuthStep;
+    }
+
+    // Where an exchange stands after the client's latest data
+    #[derive(Debug, PartialEq)]
+    pub enum AuthStep {
+        // send this data back to the client and wait for its answer
+        Continue(Vec<u8>),
+        // the client proved who it is, data goes out with the CONNACK or final AUTH
+        Success { user_name: String, data: Option<Vec<u8>> },
+        // the client failed to authenticate
+        Failure,
+    }
+
+    // A user's salted password hash
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Credentials {
+        pub iterations: u32,
+        pub salt: Vec<u8>,
+        // PBKDF2-HMAC-SHA256 of the password
+        pub salted_password: Vec<u8>,
+    }
+
+    impl Credentials {
+        // hash a password under a new random salt
+        pub fn new(password: &str, iterations: u32) -> Self {
+            let mut salt = vec![0; 16];
+            rand::thread_rng().fill_bytes(&mut salt);
+            Self::with_salt(password, salt, iterations)
+        }
+
+        pub fn with_salt(password: &str, salt: Vec<u8>, iterations: u32) -> Self {
+            let salted_password = salt_password(password, &salt, iterations);
+            Self { iterations, salt, salted_password }
+        }
+
+        pub fn matches(&self, password: &str) -> bool {
+            let salted_password = salt_password(password, &self.salt, self.iterations);
+            constant_time_eq(&salted_password, &self.salted_password)
+        }
+    }
+
+    // Users and password hashes kept in a file, one user per line:
+    //   user:iterations:salt:hash
+    // with salt and hash in base64, blank lines and lines starting with '#' are skipped
+    #[derive(Debug, Default)]
+    pub struct PasswordFile {
+        users: HashMap<String, Credentials>,
+    }
+
+    impl PasswordFile {
+        pub fn new() -> Self {
+            Self { users: HashMap::new() }
+        }
+
+        pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+            Self::parse(&fs::read_to_string(path)?)
+        }
+
+        pub fn parse(contents: &str) -> io::Result<Self> {
+            let mut users = HashMap::new();
+
+            for (number, line) in contents.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+
+                let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Bad password entry on line {}", number + 1));
+                // user names can't hold a ':', so split from the right
+                let mut fields = line.rsplitn(4, ':');
+                let salted_password = fields.next().and_then(|f| BASE64.decode(f).ok()).ok_or_else(invalid)?;
+                let salt = fields.next().and_then(|f| BASE64.decode(f).ok()).ok_or_else(invalid)?;
+                let iterations = fields.next().and_then(|f| f.parse().ok()).ok_or_else(invalid)?;
+                let user = fields.next().ok_or_else(invalid)?;
+
+                users.insert(user.to_string(), Credentials { iterations, salt, salted_password });
+            }
+
+            Ok(Self { users })
+        }
+
+        pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+            let mut users: Vec<_> = self.users.iter().collect();
+            users.sort_by(|a, b| a.0.cmp(b.0));
+
+            let contents: String = users
+                .into_iter()
+                .map(|(user, c)| {
+                    format!("{}:{}:{}:{}\n", user, c.iterations, BASE64.encode(&c.salt), BASE64.encode(&c.salted_password))
+                })
+                .collect();
+            fs::write(path, contents)
+        }
+
+        // add a user, or give an existing one a new password
+        pub fn set_password(&mut self, user: &str, password: &str, iterations: u32) -> io::Result<()> {
+            if user.is_empty() || user.contains(':') {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "User names can't be empty or contain ':'"));
+            }
+            self.users.insert(user.to_string(), Credentials::new(password, iterations));
+            Ok(())
+        }
+
+        pub fn credentials(&self, user: &str) -> Option<&Credentials> {
+            self.users.get(user)
+        }
+    }
+
+    impl Authenticator for PasswordFile {
+        fn authenticate(&self, _client_id: &str, user_name: Option<&str>, password: Option<&str>) -> ConnectReason {
+            // anonymous clients aren't let in once there is a password file
+            let user_name = match user_name {
+                Some(user_name) => user_name,
+                None => return ConnectReason::NotAuthorized,
+            };
+
+            match (self.users.get(user_name), password) {
+                (Some(credentials), Some(password)) if credentials.matches(password) => ConnectReason::Success,
+                _ => ConnectReason::BadUserNameOrPassword,
+            }
+        }
+    }
+
+    // SCRAM-SHA-256 (RFC 5802, RFC 7677) over the password file's salted passwords
+    pub struct ScramSha256 {
+        users: Arc<PasswordFile>,
+        // derives the salts shown for users that don't exist
+        fake_salt_key: [u8; 32],
+    }
+
+    impl ScramSha256 {
+        pub fn new(users: Arc<PasswordFile>) -> Self {
+            let mut fake_salt_key = [0; 32];
+            rand::thread_rng().fill_bytes(&mut fake_salt_key);
+            Self { users, fake_salt_key }
+        }
+    }
+
+    impl AuthMechanism for ScramSha256 {
+        fn name(&self) -> &str {
+            "SCRAM-SHA-256"
+        }
+
+        fn start(&self) -> Box<dyn AuthExchange> {
+            let mut nonce = [0; 18];
+            rand::thread_rng().fill_bytes(&mut nonce);
+            Box::new(ScramExchange::new(Arc::clone(&self.users), BASE64.encode(nonce), self.fake_salt_key))
+        }
+    }
+
+    // What the server remembers between the first and final client messages
+    struct ScramFirst {
+        user_name: String,
+        credentials: Credentials,
+        // false when the credentials were made up for a user that doesn't exist
+        known_user: bool,
+        gs2_header: String,
+        nonce: String,
+        client_first_bare: String,
+        server_first: String,
+    }
+
+    pub struct ScramExchange {
+        users: Arc<PasswordFile>,
+        server_nonce: String,
+        fake_salt_key: [u8; 32],
+        // set once the client-first-message has been answered
+        first: Option<ScramFirst>,
+        done: bool,
+    }
+
+    impl ScramExchange {
+        pub fn new(users: Arc<PasswordFile>, server_nonce: String, fake_salt_key: [u8; 32]) -> Self {
+            Self { users, server_nonce, fake_salt_key, first: None, done: false }
+        }
+
+        // credentials for a user that doesn't exist, so the exchange goes on and fails at the proof
+        // like a wrong password; the salt stays the same for the user name, as a real one would
+        fn fake_credentials(&self, user_name: &str) -> Credentials {
+            let salt = hmac_sha256(&self.fake_salt_key, format!("salt:{}", user_name).as_bytes());
+            let mut salted_password = vec![0; 32];
+            rand::thread_rng().fill_bytes(&mut salted_password);
+            Credentials { iterations: DEFAULT_ITERATIONS, salt: salt[..16].to_vec(), salted_password }
+        }
+
+        // client-first-message: gs2-header client-first-message-bare, like "n,,n=user,r=nonce"
+        fn client_first(&mut self, message: &str) -> Option<Vec<u8>> {
+            // no channel binding over MQTT, and no authorization identity
+            let client_first_bare = message.strip_prefix("n,,").or_else(|| message.strip_prefix("y,,"))?;
+            let gs2_header = &message[..3];
+
+            let mut attributes = client_first_bare.split(',');
+            let user_name = unescape_user_name(attributes.next()?.strip_prefix("n=")?)?;
+            let client_nonce = attributes.next()?.strip_prefix("r=")?;
+            if client_nonce.is_empty() {
+                return None;
+            }
+
+            let (credentials, known_user) = match self.users.credentials(&user_name) {
+                Some(credentials) => (credentials.clone(), true),
+                None => (self.fake_credentials(&user_name), false),
+            };
+            let nonce = format!("{}{}", client_nonce, self.server_nonce);
+            let server_first = format!("r={},s={},i={}", nonce, BASE64.encode(&credentials.salt), credentials.iterations);
+
+            self.first = Some(ScramFirst {
+                user_name,
+                credentials,
+                known_user,
+                gs2_header: gs2_header.to_string(),
+                nonce,
+                client_first_bare: client_first_bare.to_string(),
+                server_first: server_first.clone(),
+            });
+            Some(server_first.into_bytes())
+        }
+
+        // client-final-message: "c=channel binding,r=nonce,p=proof"
+        fn client_final(first: &ScramFirst, message: &str) -> Option<AuthStep> {
+            let (without_proof, proof) = message.rsplit_once(",p=")?;
+            let proof = BASE64.decode(proof).ok()?;
+
+            let mut attributes = without_proof.split(',');
+            let channel_binding = BASE64.decode(attributes.next()?.strip_prefix("c=")?).ok()?;
+            let nonce = attributes.next()?.strip_prefix("r=")?;
+            if channel_binding != first.gs2_header.as_bytes() || nonce != first.nonce {
+                return None;
+            }
+
+            let auth_message = format!("{},{},{}", first.client_first_bare, first.server_first, without_proof);
+            let client_key = hmac_sha256(&first.credentials.salted_password, b"Client Key");
+            let stored_key = Sha256::digest(&client_key);
+            let client_signature = hmac_sha256(&stored_key, auth_message.as_bytes());
+
+            // the proof is the client key hidden under the signature
+            if proof.len() != client_signature.len() {
+                return None;
+            }
+            let proven_key: Vec<u8> = proof.iter().zip(&client_signature).map(|(p, s)| p ^ s).collect();
+            if !constant_time_eq(&Sha256::digest(&proven_key), &stored_key) || !first.known_user {
+                return None;
+            }
+
+            // prove the server knows the password too
+            let server_key = hmac_sha256(&first.credentials.salted_password, b"Server Key");
+            let server_signature = hmac_sha256(&server_key, auth_message.as_bytes());
+            Some(AuthStep::Success {
+                user_name: first.user_name.clone(),
+                data: Some(format!("v={}", BASE64.encode(server_signature)).into_bytes()),
+            })
+        }
+    }
+
+    impl AuthExchange for ScramExchange {
+        fn step(&mut self, data: &[u8]) -> AuthStep {
+            let message = match std::str::from_utf8(data) {
+                Ok(message) if !self.done => message,
+                _ => return AuthStep::Failure,
+            };
+
+            let step = match &self.first {
+                None => self.client_first(message).map(AuthStep::Continue),
+                Some(first) => Self::client_final(first, message),
+            };
+            // a failed step ends the exchange, and so does the final one
+            if !matches!(step, Some(AuthStep::Continue(_))) {
+                self.done = true;
+            }
+            step.unwrap_or(AuthStep::Failure)
+        }
+    }
+
+    // SCRAM user names escape ',' as "=2C" and '=' as "=3D"
+    fn unescape_user_name(name: &str) -> Option<String> {
+        let mut unescaped = String::with_capacity(name.len());
+        let mut rest = name;
+        while let Some(pos) = rest.find('=') {
+            unescaped.push_str(&rest[..pos]);
+            match rest.get(pos..pos + 3) {
+                Some("=2C") => unescaped.push(','),
+                Some("=3D") => unescaped.push('='),
+                _ => return None,
+            }
+            rest = &rest[pos + 3..];
+        }
+        unescaped.push_str(rest);
+        Some(unescaped)
+    }
+
+    fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+        mac.update(message);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    pub fn salt_password(password: &str, salt: &[u8], iterations: u32) -> Vec<u8> {
+        let mut salted_password = vec![0; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut salted_password);
+        salted_password
+    }
+
+    // compare without returning early, so timing doesn't leak how much matched
+    pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+    }
+}
@@ -0,0 +1,72 @@
+#[allow(clippy::module_inception)]
+pub mod retained {
+    use mqtt_v5::topic::{Topic, TopicFilter, TopicLevel};
+    use mqtt_v5::types::PublishPacket;
+    use std::collections::HashMap;
+
+    // Last retained message for each topic
+    #[derive(Debug, Default)]
+    pub struct RetainedStore {
+        messages: HashMap<String, PublishPacket>,
+    }
+
+    impl RetainedStore {
+        pub fn new() -> Self {
+            Self { messages: HashMap::new() }
+        }
+
+        // keep a retained publish, replacing the topic's previous message
+        // an empty payload clears the topic instead
+        pub fn insert(&mut self, packet: PublishPacket) {
+            let topic = packet.topic.topic_name().to_string();
+            if packet.payload.is_empty() {
+                self.messages.remove(&topic);
+            } else {
+                // the publisher's topic alias means nothing to whoever subscribes later
+                self.messages.insert(topic, PublishPacket { topic_alias: None, ..packet });
+            }
+        }
+
+        // retained messages whose topic matches the filter
+        pub fn matching(&self, filter: &TopicFilter) -> Vec<&PublishPacket> {
+            self.messages.values().filter(|packet| topic_matches(filter, &packet.topic)).collect()
+        }
+
+        #[allow(dead_code)]
+        pub fn len(&self) -> usize {
+            self.messages.len()
+        }
+
+        #[allow(dead_code)]
+        pub fn is_empty(&self) -> bool {
+            self.messages.is_empty()
+        }
+    }
+
+    // whether a concrete topic matches a subscription filter
+    pub fn topic_matches(filter: &TopicFilter, topic: &Topic) -> bool {
+        let mut filter_levels = filter.levels();
+        let mut topic_levels = topic.levels();
+
+        // Don't allow wildcard filters to match topics
+        // with leading dollar signs, like '$SYS/stats'
+        if topic.topic_name().starts_with('$') {
+            if let Some(TopicLevel::SingleLevelWildcard) | Some(TopicLevel::MultiLevelWildcard) =
+                filter.levels().next()
+            {
+                return false;
+            }
+        }
+
+        loop {
+            match (filter_levels.next(), topic_levels.next()) {
+                // '#' matches this level, everything below it, and the parent level
+                (Some(TopicLevel::MultiLevelWildcard), _) => return true,
+                (Some(TopicLevel::SingleLevelWildcard), Some(_)) => {},
+                (Some(TopicLevel::Concrete(f)), Some(TopicLevel::Concrete(t))) if f == t => {},
+                (None, None) => return true,
+                _ => return false,
+            }
+        }
+    }
+}
@@ -0,0 +1,302 @@
+#[allow(clippy::module_inception)]
+pub mod session {
+    use mqtt_v5::topic::TopicFilter;
+    use mqtt_v5::types::{DisconnectReason, Packet, PublishPacket, PublishReleasePacket, PublishReleaseReason, QoS};
+    use std::collections::{HashSet, VecDeque};
+    use std::sync::mpsc::Sender;
+    use std::time::{Duration, Instant};
+
+    use crate::broker::broker::disconnect_packet;
+
+    // A message the broker sent that the client hasn't finished acknowledging
+    #[derive(Debug)]
+    enum Inflight {
+        // waiting for PUBACK (QoS 1) or PUBREC (QoS 2)
+        Publish(PublishPacket),
+        // QoS 2 message the client has received, waiting for PUBCOMP
+        Release(u16),
+    }
+
+    impl Inflight {
+        fn packet_id(&self) -> Option<u16> {
+            match self {
+                Inflight::Publish(p) => p.packet_id,
+                Inflight::Release(packet_id) => Some(*packet_id),
+            }
+        }
+    }
+
+    // Per-client state that outlives a single connection
+    #[derive(Debug)]
+    pub struct Session {
+        // queue read by the client's connection, None while disconnected
+        outbound: Option<Sender<Packet>>,
+        // which connection the queue belongs to
+        connection_id: u64,
+        next_packet_id: u16,
+        // most QoS 1 and 2 messages the client will take unacknowledged
+        receive_maximum: u16,
+        // sent and waiting for an ack, oldest first
+        inflight: VecDeque<Inflight>,
+        // waiting for room in the inflight window
+        pending: VecDeque<PublishPacket>,
+        // QoS 2 packet ids from the client that were received but not yet released
+        received: HashSet<u16>,
+        // the client's filters and their subscription tree tokens
+        subscriptions: Vec<(TopicFilter, u64)>,
+        // seconds the session is kept after a disconnect, u32::MAX never expires
+        expiry_interval: u32,
+        // when the last connection went away, None while connected
+        disconnected_at: Option<Instant>,
+        // published if the connection drops without a normal DISCONNECT
+        will: Option<PublishPacket>,
+        // seconds to hold the will back after the connection drops
+        will_delay: u32,
+        // when the will goes out, set once the connection has dropped
+        will_at: Option<Instant>,
+        // who the client authenticated as, for access checks
+        user_name: Option<String>,
+    }
+
+    impl Session {
+        pub fn new() -> Self {
+            Self {
+                outbound: None,
+                connection_id: 0,
+                next_packet_id: 1,
+                receive_maximum: u16::MAX,
+                inflight: VecDeque::new(),
+                pending: VecDeque::new(),
+                received: HashSet::new(),
+                subscriptions: Vec::new(),
+                expiry_interval: 0,
+                disconnected_at: None,
+                will: None,
+                will_delay: 0,
+                will_at: None,
+                user_name: None,
+            }
+        }
+
+        pub fn set_user_name(&mut self, user_name: Option<String>) {
+            self.user_name = user_name;
+        }
+
+        pub fn user_name(&self) -> Option<&str> {
+            self.user_name.as_deref()
+        }
+
+        // the will from the latest CONNECT, replacing one still waiting to go out
+        pub fn set_will(&mut self, will: Option<PublishPacket>, will_delay: u32) {
+            self.will = will;
+            self.will_delay = will_delay;
+            self.will_at = None;
+        }
+
+        // the client disconnected normally, so the will isn't sent
+        pub fn clear_will(&mut self) {
+            self.set_will(None, 0);
+        }
+
+        // the will, once the connection has been gone for the will delay
+        pub fn take_due_will(&mut self, now: Instant) -> Option<PublishPacket> {
+            match self.will_at {
+                Some(at) if at <= now => self.take_will(),
+                _ => None,
+            }
+        }
+
+        // the will regardless of its delay, for when the session ends
+        pub fn take_will(&mut self) -> Option<PublishPacket> {
+            self.will_at = None;
+            self.will.take()
+        }
+
+        pub fn set_expiry_interval(&mut self, expiry_interval: u32) {
+            self.expiry_interval = expiry_interval;
+        }
+
+        pub fn expiry_interval(&self) -> u32 {
+            self.expiry_interval
+        }
+
+        // whether the session has been disconnected for longer than its expiry interval
+        pub fn is_expired(&self, now: Instant) -> bool {
+            match self.disconnected_at {
+                Some(_) if self.expiry_interval == u32::MAX => false,
+                Some(at) => now.saturating_duration_since(at) >= Duration::from_secs(self.expiry_interval.into()),
+                None => false,
+            }
+        }
+
+        // record a subscription, returns the token of the one it replaces
+        pub fn add_subscription(&mut self, filter: TopicFilter, token: u64) -> Option<u64> {
+            let replaced = self.remove_subscription(&filter);
+            self.subscriptions.push((filter, token));
+            replaced
+        }
+
+        // forget a subscription, returns its token if the client had one
+        pub fn remove_subscription(&mut self, filter: &TopicFilter) -> Option<u64> {
+            let pos = self.subscriptions.iter().position(|(f, _)| f == filter)?;
+            Some(self.subscriptions.remove(pos).1)
+        }
+
+        // every subscription, for removing them from the tree when the session ends
+        pub fn take_subscriptions(&mut self) -> Vec<(TopicFilter, u64)> {
+            std::mem::take(&mut self.subscriptions)
+        }
+
+        pub fn set_receive_maximum(&mut self, receive_maximum: u16) {
+            // zero is a protocol error, treat it as no limit rather than stalling
+            self.receive_maximum = if receive_maximum == 0 { u16::MAX } else { receive_maximum };
+        }
+
+        pub fn is_connected(&self) -> bool {
+            self.outbound.is_some()
+        }
+
+        // id of the connection currently attached, if any
+        pub fn connection_id(&self) -> Option<u64> {
+            self.outbound.as_ref().map(|_| self.connection_id)
+        }
+
+        // a connection took over this session
+        // anything sent before and never acknowledged goes out again,
+        // as a duplicate PUBLISH or as another PUBREL
+        pub fn attach(&mut self, connection_id: u64, outbound: Sender<Packet>) {
+            self.outbound = Some(outbound);
+            self.connection_id = connection_id;
+            self.disconnected_at = None;
+
+            for inflight in &self.inflight {
+                let packet = match inflight {
+                    Inflight::Publish(p) => Packet::Publish(PublishPacket { is_duplicate: true, ..p.clone() }),
+                    Inflight::Release(packet_id) => Packet::PublishRelease(release_packet(*packet_id)),
+                };
+                self.send(packet);
+            }
+            self.fill_window();
+        }
+
+        // the connection went away, keep the state for the next one
+        pub fn detach(&mut self) {
+            self.outbound = None;
+            let now = Instant::now();
+            self.disconnected_at = Some(now);
+
+            // the will waits out its delay, but never outlives the session
+            if self.will.is_some() {
+                let delay = self.will_delay.min(self.expiry_interval);
+                self.will_at = Some(now + Duration::from_secs(delay.into()));
+            }
+        }
+
+        // another connection connected with the same client id,
+        // tell the current one to go away and detach it
+        pub fn take_over(&mut self) {
+            self.close(DisconnectReason::SessionTakenOver);
+            self.detach();
+        }
+
+        // have the connection send DISCONNECT after what's already queued and close,
+        // the session stays attached until the connection is gone
+        pub fn close(&mut self, reason: DisconnectReason) {
+            self.send(Packet::Disconnect(disconnect_packet(reason)));
+        }
+
+        // queue a message routed to this client
+        pub fn publish(&mut self, packet: PublishPacket) {
+            if packet.qos == QoS::AtMostOnce {
+                // QoS 0 is fire and forget
+                self.send(Packet::Publish(packet));
+            } else {
+                self.pending.push_back(packet);
+                self.fill_window();
+            }
+        }
+
+        // PUBACK or PUBCOMP from the client, returns false if the packet id wasn't inflight
+        pub fn acknowledge(&mut self, packet_id: u16) -> bool {
+            match self.inflight.iter().position(|p| p.packet_id() == Some(packet_id)) {
+                Some(pos) => {
+                    self.inflight.remove(pos);
+                    self.fill_window();
+                    true
+                },
+                None => false,
+            }
+        }
+
+        // PUBREC from the client for a QoS 2 message, answered with PUBREL
+        // returns false if the packet id wasn't waiting on a PUBREC
+        pub fn release(&mut self, packet_id: u16) -> bool {
+            let pos = self.inflight.iter().position(|p| {
+                matches!(p, Inflight::Publish(p) if p.packet_id == Some(packet_id) && p.qos == QoS::ExactlyOnce)
+            });
+
+            match pos {
+                Some(pos) => {
+                    self.inflight[pos] = Inflight::Release(packet_id);
+                    self.send(Packet::PublishRelease(release_packet(packet_id)));
+                    true
+                },
+                None => false,
+            }
+        }
+
+        // QoS 2 PUBLISH from the client
+        // returns false if the packet id is already held, meaning this is a duplicate
+        pub fn receive(&mut self, packet_id: u16) -> bool {
+            self.received.insert(packet_id)
+        }
+
+        // PUBREL from the client, returns false if the packet id wasn't held
+        pub fn complete(&mut self, packet_id: u16) -> bool {
+            self.received.remove(&packet_id)
+        }
+
+        // send pending messages while there is room in the window
+        fn fill_window(&mut self) {
+            while self.is_connected() && self.inflight.len() < self.receive_maximum as usize {
+                let mut packet = match self.pending.pop_front() {
+                    Some(packet) => packet,
+                    None => break,
+                };
+
+                packet.packet_id = Some(self.allocate_packet_id());
+                self.inflight.push_back(Inflight::Publish(packet.clone()));
+                self.send(Packet::Publish(packet));
+            }
+        }
+
+        // next packet id that isn't already waiting on an ack
+        fn allocate_packet_id(&mut self) -> u16 {
+            loop {
+                let packet_id = self.next_packet_id;
+                // packet ids run 1..=65535
+                self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+
+                if !self.inflight.iter().any(|p| p.packet_id() == Some(packet_id)) {
+                    return packet_id;
+                }
+            }
+        }
+
+        fn send(&self, packet: Packet) {
+            if let Some(outbound) = &self.outbound {
+                // the connection may have closed since it attached
+                let _ = outbound.send(packet);
+            }
+        }
+    }
+
+    fn release_packet(packet_id: u16) -> PublishReleasePacket {
+        PublishReleasePacket {
+            packet_id,
+            reason_code: PublishReleaseReason::Success,
+            reason_string: None,
+            user_properties: Vec::new(),
+        }
+    }
+}
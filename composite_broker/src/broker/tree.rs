@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod tree {
     use mqtt_v5::topic::{Topic, TopicFilter, TopicLevel};
     use std::collections::{hash_map::Entry, HashMap};
@@ -164,7 +165,7 @@ pub mod tree {
 
             // Go up the stack, cleaning up empty nodes
             while let Some((stack_val, level_index)) = stack.pop() {
-                let mut tree = unsafe { &mut *stack_val };
+                let tree = unsafe { &mut *stack_val };
 
                 let level = &levels[level_index];
 
@@ -199,43 +200,45 @@ pub mod tree {
             let mut tree_stack = vec![(self, 0)];
             let levels: Vec<TopicLevel> = topic.levels().collect();
 
-            while !tree_stack.is_empty() {
-                let (current_tree, current_level) = tree_stack.pop().unwrap();
+            while let Some((current_tree, current_level)) = tree_stack.pop() {
                 let level = &levels[current_level];
 
                 // Don't allow wildcard subscribers to receive messages
                 // with leading dollar signs, like '$SYS/stats'
-                // if current_level != 0 || !level.has_leading_dollar() {
-                //     subscriptions.extend(
-                //         current_tree.multi_level_wildcards.iter().map(|(_, subscriber)| subscriber),
-                //     );
-                // }
-
-                // if let Some(sub_tree) = &current_tree.single_level_wildcards {
-                //     // Don't allow wildcard subscribers to receive messages
-                //     // with leading dollar signs, like '$SYS/stats'
-                //     if current_level != 0 || !level.has_leading_dollar() {
-                //         if current_level + 1 < levels.len() {
-                //             tree_stack.push((sub_tree, current_level + 1));
-                //         } else {
-                //             subscriptions
-                //                 .extend(sub_tree.subscribers.iter().map(|(_, subscriber)| subscriber));
-                //         }
-                //     }
-                // }
+                let wildcards_allowed = current_level != 0 || !has_leading_dollar(level);
 
-                if let TopicLevel::Concrete(level) = level {
-                    if current_tree.concrete_topic_levels.contains_key(*level) {
-                        let sub_tree = current_tree.concrete_topic_levels.get(*level).unwrap();
+                // '#' matches this level and everything below it
+                if wildcards_allowed {
+                    subscriptions.extend(
+                        current_tree.multi_level_wildcards.iter().map(|(_, subscriber)| subscriber),
+                    );
+                }
+
+                if let Some(sub_tree) = &current_tree.single_level_wildcards {
+                    if wildcards_allowed {
+                        if current_level + 1 < levels.len() {
+                            tree_stack.push((sub_tree, current_level + 1));
+                        } else {
+                            subscriptions
+                                .extend(sub_tree.subscribers.iter().map(|(_, subscriber)| subscriber));
+
+                            // 'a/+/#' also matches 'a/b'
+                            subscriptions.extend(
+                                sub_tree.multi_level_wildcards.iter().map(|(_, subscriber)| subscriber),
+                            );
+                        }
+                    }
+                }
 
+                if let TopicLevel::Concrete(level) = level {
+                    if let Some(sub_tree) = current_tree.concrete_topic_levels.get(*level) {
                         if current_level + 1 < levels.len() {
-                            let sub_tree = current_tree.concrete_topic_levels.get(*level).unwrap();
                             tree_stack.push((sub_tree, current_level + 1));
                         } else {
                             subscriptions
                                 .extend(sub_tree.subscribers.iter().map(|(_, subscriber)| subscriber));
 
-                            // TODO(bschwind) - Verify this works properly with better tests.
+                            // 'a/#' also matches the parent level 'a'
                             subscriptions.extend(
                                 sub_tree.multi_level_wildcards.iter().map(|(_, subscriber)| subscriber),
                             );
@@ -246,4 +249,8 @@ pub mod tree {
             subscriptions.into_iter()
         }
     }
+
+    fn has_leading_dollar(level: &TopicLevel) -> bool {
+        matches!(level, TopicLevel::Concrete(name) if name.starts_with('$'))
+    }
 }
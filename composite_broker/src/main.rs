@@ -1,96 +1,742 @@
 mod broker;
 mod msg_parser;
+mod tls;
+mod websocket;
+use std::env;
 use std::io;
-// use bytes::{BytesMut};
-use std::time;
-use std ::net::{TcpListener,TcpStream};
+use std::path::Path;
+use bytes::{Bytes, BytesMut};
+use std ::net::{Shutdown,TcpListener,TcpStream};
 use std::io::{Read,Write};
+use std::sync::{Arc,Mutex};
+use std::sync::mpsc::{self,Receiver,Sender};
 use std::thread;
-use crate::msg_parser::msg_parser::{cm_decode};
-use mqtt_v5::types::{Packet}; // ConnectPacket. decoder, ProtocolVersion
-
-// Handle access stream; create a struct to hold the stream’s state
-// Perform I/O operations
-fn handle_sender(mut stream: TcpStream) -> io::Result<()>{
-
-    // Handle multiple access stream
-    let mut buf = [0;512];
-    for _ in 0..1000{
-        let bytes_read = stream.read(&mut buf)?;  // let the receiver get a message from a sender
-        
-        if bytes_read == 0 {
-            // sender stream in a mutable variable
-            return Ok(());
+use std::time::{Duration, Instant};
+use crate::broker::acl::acl::AclFile;
+use crate::broker::auth::auth::{AuthExchange, AuthStep, PasswordFile, ScramSha256, DEFAULT_ITERATIONS};
+use crate::broker::broker::{connect_ack, disconnect_packet, MBroker};
+use crate::msg_parser::msg_parser::{cm_encode, ParseError, StreamDecoder};
+use crate::tls::tls::{self as broker_tls, TlsStream};
+use crate::websocket::websocket::{self as broker_ws, WsStream};
+use mqtt_v5::types::{AuthenticatePacket, AuthenticateReason, ConnectPacket, ConnectReason, DisconnectReason, Packet};
+use mqtt_v5::types::properties::{AuthenticationData, AuthenticationMethod};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+// How long a read waits before the connection checks its outbound queue
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// How often the broker checks for expired sessions and delayed wills
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long shutdown waits for connections to write out what's queued for them
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long a client gets to finish the TLS or WebSocket handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A client's byte stream, plain TCP, TLS over it, or WebSocket binary messages
+trait Transport: Read + Write + Send {
+    // who the transport has already proven the client to be, like a client certificate's CN
+    fn peer_identity(&self) -> Option<String> {
+        None
+    }
+
+    // end the stream, and the socket under it
+    fn close(&mut self);
+}
+
+impl Transport for TcpStream {
+    fn close(&mut self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+impl Transport for TlsStream {
+    fn peer_identity(&self) -> Option<String> {
+        broker_tls::peer_common_name(&self.conn)
+    }
+
+    fn close(&mut self) {
+        self.conn.send_close_notify();
+        let _ = self.flush();
+        let _ = self.sock.shutdown(Shutdown::Both);
+    }
+}
+
+impl Transport for WsStream {
+    fn close(&mut self) {
+        self.shutdown();
+    }
+}
+
+// Where a connection is in the MQTT handshake
+#[derive(Debug, PartialEq)]
+enum ConnState {
+    // waiting for the first packet, which must be CONNECT
+    AwaitingConnect,
+    // CONNECT named an authentication method, waiting for the client's next AUTH
+    Authenticating,
+    // CONNECT accepted for this client id, any other packet may follow
+    Connected(String),
+}
+
+// What to do with the connection after handling a packet
+#[derive(Debug, PartialEq)]
+enum Flow {
+    Continue,
+    Close,
+}
+
+// Holds the stream's state
+struct Connection<S: Transport> {
+    stream: S,
+    broker: Arc<Mutex<MBroker>>,
+    state: ConnState,
+    // messages routed to this client by other connections
+    outbound_tx: Sender<Packet>,
+    outbound_rx: Receiver<Packet>,
+    // given by the broker when the connection registers
+    connection_id: u64,
+    // how long the client may stay silent, None if keep-alive is off
+    keep_alive: Option<Duration>,
+    // when the last packet arrived from the client
+    last_packet: Instant,
+    // the CONNECT waiting on its authentication exchange
+    pending_connect: Option<ConnectPacket>,
+    // the authentication method from CONNECT, None if the client didn't use one
+    auth_method: Option<String>,
+    // who the authentication exchange said the client is
+    auth_user: Option<String>,
+    // the exchange in progress, for CONNECT or a re-authentication
+    exchange: Option<Box<dyn AuthExchange>>,
+}
+
+impl<S: Transport> Connection<S> {
+    fn new(stream: S, broker: Arc<Mutex<MBroker>>) -> Self {
+        let (outbound_tx, outbound_rx) = mpsc::channel();
+        Self {
+            stream,
+            broker,
+            state: ConnState::AwaitingConnect,
+            outbound_tx,
+            outbound_rx,
+            connection_id: 0,
+            keep_alive: None,
+            last_packet: Instant::now(),
+            pending_connect: None,
+            auth_method: None,
+            auth_user: None,
+            exchange: None,
         }
+    }
 
-        stream.write(&buf[..bytes_read])?;
+    // encode a packet and write it to the stream
+    fn send_packet(&mut self, packet: Packet) -> io::Result<()> {
+        let mut buf = BytesMut::new();
+        cm_encode(packet, &mut buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        self.stream.write_all(&buf)?;
+        self.stream.flush()
+    }
 
-        // Read and determine path for message
-        if String::from_utf8_lossy(&buf).starts_with("mqtt") {// contains("connect") {
-            // if "connect" message received, alert client to send connect packet
-            println!("Incoming command from client");
+    // write out everything queued for this client
+    // a DISCONNECT from the broker ends the connection
+    fn flush_outbound(&mut self) -> io::Result<Flow> {
+        while let Ok(packet) = self.outbound_rx.try_recv() {
+            let disconnect = matches!(packet, Packet::Disconnect(_));
+            self.send_packet(packet)?;
+            if disconnect {
+                return Ok(Flow::Close);
+            }
         }
-        else {
-            // decoding packet 
-            let decode = cm_decode(&mut buf);
+        Ok(Flow::Continue)
+    }
 
-            match decode {
-                Ok(Packet::Connect(p)) => 
-                    println!("\tConnect packet received with ID {}\n", p.client_id)
-                ,
-                Ok(Packet::Publish(p)) => 
-                    println!("\tPublish packet received for topic {} with date: {}", p.topic.to_string().trim(), String::from_utf8_lossy(&p.payload))
-                ,
-                Ok(Packet::Subscribe(p)) => 
-                    println!("\tSubscribe packet received with packet ID {} and topic {}", p.packet_id, p.subscription_topics[0].topic_filter.to_string())
-                ,
-                _ => panic!("Incorrect type returned"),
+    // Handle a single packet from the client
+    fn handle_packet(&mut self, packet: Packet) -> io::Result<Flow> {
+        // the first packet has to be CONNECT, and it can only be sent once
+        match (&self.state, packet) {
+            (ConnState::AwaitingConnect, Packet::Connect(p)) => {
+                println!("\tConnect packet received with ID {}", p.client_id);
+                match p.authentication_method.as_ref().map(|m| m.0.clone()) {
+                    Some(method) => self.begin_auth(p, method),
+                    None => self.connect(p, None),
+                }
+            },
+            (ConnState::AwaitingConnect, _) => {
+                eprintln!("First packet was not CONNECT, closing connection");
+                Ok(Flow::Close)
+            },
+            (ConnState::Authenticating, Packet::Authenticate(p)) => self.continue_auth(p),
+            (ConnState::Authenticating, _) => {
+                eprintln!("Expected AUTH during authentication, closing connection");
+                self.refuse(ConnectReason::ProtocolError)
+            },
+            (ConnState::Connected(_), Packet::Connect(_)) => {
+                eprintln!("Second CONNECT received, closing connection");
+                self.disconnect(DisconnectReason::ProtocolError)
+            },
+            (ConnState::Connected(client_id), Packet::Publish(p)) => {
+                // payloads can be private to the clients the ACLs allow, so only the size is logged
+                println!("\tPublish packet received for topic {} with {} bytes", p.topic, p.payload.len());
+                let pub_ack = {
+                    let broker = &mut *self.broker.lock().unwrap();
+                    let (deliveries, pub_ack) = broker.accept_publish(client_id, p);
+                    broker.deliver(deliveries);
+                    pub_ack
+                };
+
+                // PUBACK for QoS 1, PUBREC for QoS 2, DISCONNECT if the publish wasn't allowed at all
+                match pub_ack {
+                    Some(Packet::Disconnect(p)) => {
+                        eprintln!("Publish refused with {:?}, closing connection", p.reason_code);
+                        self.send_packet(Packet::Disconnect(p))?;
+                        Ok(Flow::Close)
+                    },
+                    Some(pub_ack) => {
+                        self.send_packet(pub_ack)?;
+                        Ok(Flow::Continue)
+                    },
+                    None => Ok(Flow::Continue),
+                }
+            },
+            (ConnState::Connected(client_id), Packet::PublishRelease(p)) => {
+                let pub_comp = self.broker.lock().unwrap().accept_pubrel(client_id, p);
+                self.send_packet(Packet::PublishComplete(pub_comp))?;
+                Ok(Flow::Continue)
+            },
+            (ConnState::Connected(client_id), Packet::Subscribe(p)) => {
+                println!("\tSubscribe packet received with packet ID {}", p.packet_id);
+                let sub_ack = self.broker.lock().unwrap().accept_sub(client_id, p);
+                self.send_packet(Packet::SubscribeAck(sub_ack))?;
+                Ok(Flow::Continue)
+            },
+            (ConnState::Connected(_), Packet::PingRequest) => {
+                self.send_packet(Packet::PingResponse)?;
+                Ok(Flow::Continue)
+            },
+            (ConnState::Connected(client_id), Packet::Disconnect(p)) => {
+                println!("\tDisconnect packet received with reason {:?}", p.reason_code);
+                let result = self.broker.lock().unwrap().accept_disconnect(client_id, p);
+                match result {
+                    Ok(()) => Ok(Flow::Close),
+                    Err(reason) => self.disconnect(reason),
+                }
+            },
+            // acknowledgements for messages sent by the broker
+            (ConnState::Connected(client_id), Packet::PublishAck(p)) => {
+                self.broker.lock().unwrap().accept_puback(client_id, p);
+                Ok(Flow::Continue)
+            },
+            (ConnState::Connected(client_id), Packet::PublishReceived(p)) => {
+                self.broker.lock().unwrap().accept_pubrec(client_id, p);
+                Ok(Flow::Continue)
+            },
+            (ConnState::Connected(client_id), Packet::PublishComplete(p)) => {
+                self.broker.lock().unwrap().accept_pubcomp(client_id, p);
+                Ok(Flow::Continue)
+            },
+            (ConnState::Connected(client_id), Packet::Unsubscribe(p)) => {
+                println!("\tUnsubscribe packet received with packet ID {}", p.packet_id);
+                let unsub_ack = self.broker.lock().unwrap().accept_unsub(client_id, p);
+                self.send_packet(Packet::UnsubscribeAck(unsub_ack))?;
+                Ok(Flow::Continue)
+            },
+            (ConnState::Connected(_), Packet::Authenticate(p)) => self.reauthenticate(p),
+            // packets only a server should send
+            (ConnState::Connected(_), p) => {
+                eprintln!("Unexpected packet from client: {:?}", p);
+                self.disconnect(DisconnectReason::ProtocolError)
+            },
+        }
+    }
+
+    // run the CONNECT through the broker, after its authentication exchange if it had one
+    // auth is the user the exchange settled on and the data for the CONNACK
+    fn connect(&mut self, p: ConnectPacket, auth: Option<(String, Option<Vec<u8>>)>) -> io::Result<Flow> {
+        let mut client_id = p.client_id.clone();
+        let keep_alive = p.keep_alive;
+        // a verified client certificate already says who the client is
+        let auth = auth.or_else(|| self.stream.peer_identity().map(|identity| (identity, None)));
+        let auth_user = auth.as_ref().map(|(user_name, _)| user_name.clone());
+        let conn_ack = {
+            let mut broker = self.broker.lock().unwrap();
+            let conn_ack = match auth {
+                Some((user_name, data)) => broker.accept_authenticated_client(p, user_name, data),
+                None => broker.accept_new_client(p),
             };
+            // the broker picked an id for a client that sent an empty one
+            if let Some(assigned) = &conn_ack.assigned_client_identifier {
+                client_id = assigned.0.clone();
+            }
+            if conn_ack.reason_code == ConnectReason::Success {
+                self.connection_id = broker.register_outbound(&client_id, self.outbound_tx.clone());
+            }
+            conn_ack
+        };
+        let accepted = conn_ack.reason_code == ConnectReason::Success;
+
+        // the server's keep-alive replaces the client's, zero turns it off
+        let keep_alive = conn_ack.server_keep_alive.as_ref().map(|k| k.0).unwrap_or(keep_alive);
+        self.keep_alive = if keep_alive == 0 {
+            None
+        } else {
+            // the client gets one and a half keep-alive periods before it's dropped
+            Some(Duration::from_millis(u64::from(keep_alive) * 1500))
+        };
+        self.send_packet(Packet::ConnectAck(conn_ack))?;
+
+        if accepted {
+            self.auth_user = auth_user;
+            self.state = ConnState::Connected(client_id);
+            Ok(Flow::Continue)
+        } else {
+            Ok(Flow::Close)
         }
-        
-        // And you can sleep this connection with the connected sender
-        thread::sleep(time::Duration::from_secs(1));  
     }
-    // success value
-    Ok(())
+
+    // CONNECT named an authentication method, start an exchange with its data
+    fn begin_auth(&mut self, p: ConnectPacket, method: String) -> io::Result<Flow> {
+        let exchange = self.broker.lock().unwrap().start_auth(&method);
+        let mut exchange = match exchange {
+            Some(exchange) => exchange,
+            None => return self.refuse(ConnectReason::BadAuthenticationMethod),
+        };
+        let step = exchange.step(&authentication_data(&p.authentication_data));
+        self.auth_method = Some(method);
+        self.connect_step(p, exchange, step)
+    }
+
+    // the client's answer to a challenge sent before CONNACK
+    fn continue_auth(&mut self, p: AuthenticatePacket) -> io::Result<Flow> {
+        let (connect_packet, mut exchange) = match (self.pending_connect.take(), self.exchange.take()) {
+            (Some(connect_packet), Some(exchange)) if self.same_method(&p, AuthenticateReason::ContinueAuthentication) => {
+                (connect_packet, exchange)
+            },
+            _ => return self.refuse(ConnectReason::ProtocolError),
+        };
+        let step = exchange.step(&authentication_data(&p.authentication_data));
+        self.connect_step(connect_packet, exchange, step)
+    }
+
+    // challenge the client again, or finish the CONNECT
+    fn connect_step(&mut self, p: ConnectPacket, exchange: Box<dyn AuthExchange>, step: AuthStep) -> io::Result<Flow> {
+        match step {
+            AuthStep::Continue(data) => {
+                self.send_packet(self.auth_packet(AuthenticateReason::ContinueAuthentication, Some(data)))?;
+                self.pending_connect = Some(p);
+                self.exchange = Some(exchange);
+                self.state = ConnState::Authenticating;
+                Ok(Flow::Continue)
+            },
+            AuthStep::Success { user_name, data } => self.connect(p, Some((user_name, data))),
+            AuthStep::Failure => self.refuse(ConnectReason::NotAuthorized),
+        }
+    }
+
+    // AUTH after CONNACK, starting or continuing a re-authentication
+    // which has to use the CONNECT's method and prove the same user
+    fn reauthenticate(&mut self, p: AuthenticatePacket) -> io::Result<Flow> {
+        let exchange = if self.same_method(&p, AuthenticateReason::ReAuthenticate) && self.exchange.is_none() {
+            self.broker.lock().unwrap().start_auth(self.auth_method.as_deref().unwrap_or_default())
+        } else if self.same_method(&p, AuthenticateReason::ContinueAuthentication) {
+            self.exchange.take()
+        } else {
+            None
+        };
+        let mut exchange = match exchange {
+            Some(exchange) => exchange,
+            None => {
+                eprintln!("Unexpected AUTH from client, closing connection");
+                return self.disconnect(DisconnectReason::ProtocolError);
+            },
+        };
+
+        match exchange.step(&authentication_data(&p.authentication_data)) {
+            AuthStep::Continue(data) => {
+                self.send_packet(self.auth_packet(AuthenticateReason::ContinueAuthentication, Some(data)))?;
+                self.exchange = Some(exchange);
+                Ok(Flow::Continue)
+            },
+            AuthStep::Success { user_name, data } if self.auth_user.as_ref() == Some(&user_name) => {
+                self.send_packet(self.auth_packet(AuthenticateReason::Success, data))?;
+                Ok(Flow::Continue)
+            },
+            _ => self.disconnect(DisconnectReason::NotAuthorized),
+        }
+    }
+
+    // whether an AUTH has the reason given and the method from CONNECT
+    fn same_method(&self, p: &AuthenticatePacket, reason: AuthenticateReason) -> bool {
+        p.reason_code == reason
+            && self.auth_method.is_some()
+            && p.authentication_method.as_ref().map(|m| &m.0) == self.auth_method.as_ref()
+    }
+
+    fn auth_packet(&self, reason_code: AuthenticateReason, data: Option<Vec<u8>>) -> Packet {
+        Packet::Authenticate(AuthenticatePacket {
+            reason_code,
+            authentication_method: self.auth_method.clone().map(AuthenticationMethod),
+            authentication_data: data.map(|data| AuthenticationData(Bytes::from(data))),
+            reason_string: None,
+            user_properties: vec![],
+        })
+    }
+
+    // turn the CONNECT down before it's accepted
+    fn refuse(&mut self, reason: ConnectReason) -> io::Result<Flow> {
+        self.send_packet(Packet::ConnectAck(connect_ack(reason)))?;
+        Ok(Flow::Close)
+    }
+
+    // tell the client why the broker is closing the connection
+    fn disconnect(&mut self, reason: DisconnectReason) -> io::Result<Flow> {
+        self.send_packet(Packet::Disconnect(disconnect_packet(reason)))?;
+        Ok(Flow::Close)
+    }
+
+    // a packet that couldn't be decoded ends the connection,
+    // answered with CONNACK if it came before CONNECT was accepted and DISCONNECT after
+    fn reject(&mut self, error: ParseError) -> io::Result<()> {
+        match self.state {
+            ConnState::AwaitingConnect | ConnState::Authenticating => self.send_packet(Packet::ConnectAck(connect_ack(error.connect_reason()))),
+            ConnState::Connected(_) => self.disconnect(error.disconnect_reason()).map(|_| ()),
+        }
+    }
+
+    // whether the client has been silent for longer than keep-alive allows
+    fn keep_alive_expired(&self) -> bool {
+        match self.keep_alive {
+            Some(keep_alive) => self.last_packet.elapsed() > keep_alive,
+            None => false,
+        }
+    }
+
+    // stop routing messages to this connection and close the socket
+    fn close(&mut self) {
+        if let ConnState::Connected(client_id) = &self.state {
+            self.broker.lock().unwrap().remove_outbound(client_id, self.connection_id);
+        }
+        self.stream.close();
+    }
 }
 
+// the bytes of an authentication data property, empty if there wasn't one
+fn authentication_data(data: &Option<AuthenticationData>) -> Vec<u8> {
+    data.as_ref().map(|data| data.0.to_vec()).unwrap_or_default()
+}
+
+// Handle access stream
+// Reads packets off the stream and answers them until the client disconnects,
+// writing out messages routed to this client in between reads
+fn handle_sender<S: Transport>(stream: S, broker: Arc<Mutex<MBroker>>) -> io::Result<()>{
+    let mut conn = Connection::new(stream, broker);
+    let result = run_connection(&mut conn);
+    conn.close();
+    result
+}
+
+fn run_connection<S: Transport>(conn: &mut Connection<S>) -> io::Result<()> {
+    let mut buf = [0;4096];
+    // packets over the limit are refused from their fixed header, before they're buffered
+    let max_packet_size = conn.broker.lock().unwrap().maximum_packet_size();
+    let mut decoder = StreamDecoder::with_max_packet_size(max_packet_size as usize);
+    // keep-alive only starts with CONNACK, until then the connect timeout holds the thread
+    let connect_timeout = conn.broker.lock().unwrap().connect_timeout();
+    let opened = Instant::now();
+
+    loop {
+        match conn.stream.read(&mut buf) {
+            // client closed the connection
+            Ok(0) => return Ok(()),
+            // hand the bytes to the decoder; a packet may be split across reads
+            // or several packets may arrive in one read
+            Ok(bytes_read) => decoder.feed(&buf[..bytes_read]),
+            // nothing to read yet
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {},
+            Err(e) => return Err(e),
+        }
+
+        loop {
+            let packet = match decoder.next_packet() {
+                Ok(Some(packet)) => packet,
+                // wait for the rest of the packet
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return conn.reject(e);
+                },
+            };
+
+            conn.last_packet = Instant::now();
+            if conn.handle_packet(packet)? == Flow::Close {
+                return Ok(());
+            }
+        }
+
+        if conn.keep_alive_expired() {
+            eprintln!("Keep-alive expired, closing connection");
+            conn.disconnect(DisconnectReason::KeepAliveTimeout)?;
+            return Ok(());
+        }
+
+        if !matches!(conn.state, ConnState::Connected(_)) && opened.elapsed() > connect_timeout {
+            eprintln!("No CONNECT completed in time, closing connection");
+            return Ok(());
+        }
+
+        if conn.flush_outbound()? == Flow::Close {
+            return Ok(());
+        }
+    }
+}
+
+
+// Periodic work that isn't tied to a connection
+fn housekeeping(broker: Arc<Mutex<MBroker>>) {
+    loop {
+        thread::sleep(HOUSEKEEPING_INTERVAL);
+        let mut broker = broker.lock().unwrap();
+        broker.publish_due_wills(Instant::now());
+        broker.expire_sessions(Instant::now());
+    }
+}
+
+// Tell every client the broker is going away, and wait for their connections to close
+fn shut_down(broker: &Mutex<MBroker>) {
+    broker.lock().unwrap().shutdown();
+
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    while broker.lock().unwrap().has_connections() && Instant::now() < deadline {
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+// Accept connections on the listener, one thread per client
+fn serve(receiver_listener: TcpListener, broker: Arc<Mutex<MBroker>>) {
+    let housekeeping_broker = Arc::clone(&broker);
+    thread::spawn(move || housekeeping(housekeeping_broker));
+
+    listen(receiver_listener, broker, |stream: TcpStream| {
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        Ok(stream)
+    });
+}
+
+// Accept TLS connections on the listener, sharing the broker with the plain listener
+fn serve_tls(listener: TcpListener, broker: Arc<Mutex<MBroker>>, config: Arc<rustls::ServerConfig>) {
+    listen(listener, broker, move |stream: TcpStream| {
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let stream = broker_tls::accept(Arc::clone(&config), stream)?;
+        stream.sock.set_read_timeout(Some(POLL_INTERVAL))?;
+        Ok(stream)
+    });
+}
+
+// Accept WebSocket connections on the listener, for clients that can't open raw TCP like browsers
+fn serve_ws(listener: TcpListener, broker: Arc<Mutex<MBroker>>) {
+    listen(listener, broker, |stream: TcpStream| {
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let stream = broker_ws::accept(stream)?;
+        stream.socket().set_read_timeout(Some(POLL_INTERVAL))?;
+        Ok(stream)
+    });
+}
+
+// Hand each accepted socket to its own thread, where open turns it into the client's transport
+fn listen<S, F>(receiver_listener: TcpListener, broker: Arc<Mutex<MBroker>>, open: F)
+where
+    S: Transport + 'static,
+    F: Fn(TcpStream) -> io::Result<S> + Send + Sync + 'static,
+{
+    let open = Arc::new(open);
 
-fn main() -> io::Result<()>{
-    // Enable port 7878 binding
-    let receiver_listener = TcpListener::bind("127.0.0.1:7878").expect("Failed and bind with the sender");
-    // Getting a handle of the underlying thread.
-    let mut thread_vec: Vec<thread::JoinHandle<()>> = Vec::new();
     // listen to incoming connections messages and bind them to a sever socket address.
     for stream in receiver_listener.incoming() {
-        let stream = stream.expect("failed");
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("{:?}", e);
+                continue;
+            },
+        };
+        let broker = Arc::clone(&broker);
+        let open = Arc::clone(&open);
         // let the receiver connect with the sender
-        let handle = thread::spawn(move || {
+        thread::spawn(move || {
             //receiver failed to read from the stream
-            handle_sender(stream).unwrap_or_else(|error| eprintln!("{:?}",error))
+            open(stream)
+                .and_then(|stream| handle_sender(stream, broker))
+                .unwrap_or_else(|error| eprintln!("{:?}",error))
         });
-        
-        // Push messages in the order they are sent
-        thread_vec.push(handle);
     }
+}
+
+// Command line settings
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    // users allowed to connect, anyone can connect without one
+    password_file: Option<String>,
+    // topic access rules, every client can use every topic without one
+    acl_file: Option<String>,
+    // PEM certificate chain and private key, the TLS listener only runs with both
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    // PEM CA certificates that TLS clients must present a certificate from
+    tls_client_ca: Option<String>,
+    // port for the TLS listener, DEFAULT_TLS_PORT if not given
+    tls_port: Option<u16>,
+    // port for the WebSocket listener, which only runs when given
+    ws_port: Option<u16>,
+}
+
+const DEFAULT_TLS_PORT: u16 = 8883;
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--password-file" => {
+                let path = args.next().ok_or("--password-file needs a path")?;
+                options.password_file = Some(path.clone());
+            },
+            "--acl-file" => {
+                let path = args.next().ok_or("--acl-file needs a path")?;
+                options.acl_file = Some(path.clone());
+            },
+            "--tls-cert" => {
+                let path = args.next().ok_or("--tls-cert needs a path")?;
+                options.tls_cert = Some(path.clone());
+            },
+            "--tls-key" => {
+                let path = args.next().ok_or("--tls-key needs a path")?;
+                options.tls_key = Some(path.clone());
+            },
+            "--tls-client-ca" => {
+                let path = args.next().ok_or("--tls-client-ca needs a path")?;
+                options.tls_client_ca = Some(path.clone());
+            },
+            "--tls-port" => {
+                let port = args.next().ok_or("--tls-port needs a port")?;
+                options.tls_port = Some(port.parse().map_err(|_| format!("Bad TLS port {}", port))?);
+            },
+            "--ws-port" => {
+                let port = args.next().ok_or("--ws-port needs a port")?;
+                options.ws_port = Some(port.parse().map_err(|_| format!("Bad WebSocket port {}", port))?);
+            },
+            arg => return Err(format!("Unknown argument {}", arg)),
+        }
+    }
+
+    let tls_only = options.tls_client_ca.is_some() || options.tls_port.is_some();
+    if options.tls_cert.is_some() != options.tls_key.is_some() || (tls_only && options.tls_cert.is_none()) {
+        return Err("TLS needs both --tls-cert and --tls-key".to_string());
+    }
+    Ok(options)
+}
+
+// passwd <file> <user> <password>
+// adds the user to the password file, creating the file if needed
+fn add_user(args: &[String]) -> io::Result<()> {
+    let (path, user, password) = match args {
+        [path, user, password] => (path, user, password),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Usage: passwd <file> <user> <password>")),
+    };
+
+    let mut password_file = match PasswordFile::load(path) {
+        Ok(password_file) => password_file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => PasswordFile::new(),
+        Err(e) => return Err(e),
+    };
+    password_file.set_password(user, password, DEFAULT_ITERATIONS)?;
+    password_file.save(path)
+}
+
+fn main() -> io::Result<()>{
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("passwd") {
+        return add_user(&args[1..]);
+    }
+    let options = parse_options(&args).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
 
-    for handle in thread_vec {
-        // return each single value Output contained in the heap
-        handle.join().unwrap();
+    // broker state shared by every connection
+    let mut broker = MBroker::new();
+    if let Some(path) = &options.password_file {
+        // the same users back CONNECT passwords and SCRAM-SHA-256
+        let users = Arc::new(PasswordFile::load(path)?);
+        broker.set_authenticator(Box::new(Arc::clone(&users)));
+        broker.add_auth_mechanism(Box::new(ScramSha256::new(users)));
+    }
+    if let Some(path) = &options.acl_file {
+        broker.set_authorizer(Box::new(AclFile::load(path)?));
+    }
+    let broker = Arc::new(Mutex::new(broker));
+
+    if let (Some(cert), Some(key)) = (&options.tls_cert, &options.tls_key) {
+        let config = broker_tls::server_config(cert, key, options.tls_client_ca.as_deref().map(Path::new))?;
+        let tls_listener = TcpListener::bind(("127.0.0.1", options.tls_port.unwrap_or(DEFAULT_TLS_PORT)))?;
+        let broker = Arc::clone(&broker);
+        thread::spawn(move || serve_tls(tls_listener, broker, config));
+    }
+    if let Some(port) = options.ws_port {
+        let ws_listener = TcpListener::bind(("127.0.0.1", port))?;
+        let broker = Arc::clone(&broker);
+        thread::spawn(move || serve_ws(ws_listener, broker));
     }
+
+    // Enable port 7878 binding
+    let receiver_listener = TcpListener::bind("127.0.0.1:7878").expect("Failed and bind with the sender");
+
+    let serve_broker = Arc::clone(&broker);
+    thread::spawn(move || serve(receiver_listener, serve_broker));
+
+    // run until interrupted or terminated, then let clients know
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    signals.forever().next();
+    println!("Shutting down");
+    shut_down(&broker);
     // success value
     Ok(())
 }
 
 
 #[cfg(test)]
-
 mod tests {
-    use crate::broker::broker::MBroker;
-    use mqtt_v5::types::{Packet, PublishPacket, SubscribePacket, ConnectPacket, ConnectReason, QoS,
-        ProtocolVersion, RetainHandling, SubscriptionTopic};
+    use crate::broker::broker::{BrokerConfig, MBroker};
+    use crate::broker::retained::retained::{topic_matches, RetainedStore};
+    use crate::broker::tree::tree::SubscriptionTree;
+    use crate::broker::acl::acl::{filter_covers, Access, AclFile, Authorizer};
+    use crate::broker::auth::auth::{salt_password, AuthExchange, AuthStep, Authenticator, PasswordFile, ScramExchange,
+        ScramSha256, DEFAULT_ITERATIONS};
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
+    use hmac::{Hmac, Mac};
+    use sha2::{Digest, Sha256};
+    use crate::{parse_options, serve, serve_tls, serve_ws, shut_down, Options};
+    use crate::tls::tls as broker_tls;
+    use crate::websocket::websocket::WsStream;
+    use tungstenite::client::IntoClientRequest;
+    use tungstenite::{Message, WebSocket};
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+    use rustls::pki_types::{PrivateKeyDer, ServerName};
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use mqtt_v5::types::{AuthenticatePacket, AuthenticateReason, Packet, PublishPacket, SubscribePacket, ConnectPacket, ConnectReason, QoS,
+        DisconnectPacket, DisconnectReason, FinalWill, ProtocolVersion, PublishAckPacket, PublishAckReason,
+        PublishCompletePacket, PublishCompleteReason, PublishReceivedPacket, PublishReceivedReason,
+        PublishReleasePacket, PublishReleaseReason, RetainHandling, SubscriptionTopic,
+        SubscribeAckReason, UnsubscribeAckReason, UnsubscribePacket};
+    use mqtt_v5::types::properties::{AuthenticationData, AuthenticationMethod, MaximumPacketSize, MaximumQos, ReceiveMaximum, SessionExpiryInterval, SubscriptionIdentifier,
+        TopicAlias, WillDelayInterval};
+    use mqtt_v5::types::VariableByteInt;
     use bytes::{Bytes, BytesMut};
-    use crate::msg_parser::msg_parser::{cm_decode, cm_encode};
+    use crate::msg_parser::msg_parser::{cm_decode, cm_encode, ParseError, StreamDecoder};
     
     #[test]
     fn test_read_publish_packet() {
@@ -119,7 +765,7 @@ mod tests {
         assert!(res2.is_ok());
 
         // decode publish packet
-        let decode2 = cm_decode(&mut buf2);
+        let decode2 = cm_decode(&buf2);
 
         match decode2 {
             Ok(Packet::Publish(p)) => println!("\tPublish packet received {:?}", p.packet_id),
@@ -157,7 +803,7 @@ mod tests {
         assert!(res.is_ok());
 
         // decode the connect packet
-        let decode = cm_decode(&mut buf);
+        let decode = cm_decode(&buf);
 
         assert!(decode.is_ok());
         match decode {
@@ -251,7 +897,7 @@ mod tests {
         let mut broker = MBroker::new();
         // create a subscribe packet
         let sub_p = SubscribePacket {
-            packet_id: 01,
+            packet_id: 1,
             subscription_identifier: None,
             user_properties: Vec::new(),
             subscription_topics: vec![SubscriptionTopic {
@@ -263,8 +909,8 @@ mod tests {
             }],
         };
 
-        let res = broker.accept_sub(sub_p);
-        assert_eq!(res.packet_id, 01);
+        let res = broker.accept_sub("1004", sub_p);
+        assert_eq!(res.packet_id, 1);
     }
 
     #[test]
@@ -272,7 +918,7 @@ mod tests {
         let mut broker = MBroker::new();
         // create subscribe packets
         let sub_p1 = SubscribePacket {
-            packet_id: 01,
+            packet_id: 1,
             subscription_identifier: None,
             user_properties: Vec::new(),
             subscription_topics: vec![SubscriptionTopic {
@@ -285,7 +931,7 @@ mod tests {
         };
 
         let sub_p2 = SubscribePacket {
-            packet_id: 02,
+            packet_id: 2,
             subscription_identifier: None,
             user_properties: Vec::new(),
             subscription_topics: vec![SubscriptionTopic {
@@ -298,7 +944,7 @@ mod tests {
         };
 
         let sub_p3 = SubscribePacket {
-            packet_id: 03,
+            packet_id: 3,
             subscription_identifier: None,
             user_properties: Vec::new(),
             subscription_topics: vec![SubscriptionTopic {
@@ -310,14 +956,14 @@ mod tests {
             }],
         };
 
-        let r1 = broker.accept_sub(sub_p1);
-        assert_eq!(r1.packet_id, 01);
+        let r1 = broker.accept_sub("1004", sub_p1);
+        assert_eq!(r1.packet_id, 1);
 
-        let r2 = broker.accept_sub(sub_p2);
-        assert_eq!(r2.packet_id, 02);
+        let r2 = broker.accept_sub("1004", sub_p2);
+        assert_eq!(r2.packet_id, 2);
 
-        let r3 = broker.accept_sub(sub_p3);
-        assert_eq!(r3.packet_id, 03);
+        let r3 = broker.accept_sub("1004", sub_p3);
+        assert_eq!(r3.packet_id, 3);
     }
 
     #[test]
@@ -325,7 +971,7 @@ mod tests {
         let mut broker = MBroker::new();
         // create subscribe packets
         let sub_p1 = SubscribePacket {
-            packet_id: 01,
+            packet_id: 1,
             subscription_identifier: None,
             user_properties: Vec::new(),
             subscription_topics: vec![SubscriptionTopic {
@@ -338,7 +984,7 @@ mod tests {
         };
 
         let sub_p2 = SubscribePacket {
-            packet_id: 02,
+            packet_id: 2,
             subscription_identifier: None,
             user_properties: Vec::new(),
             subscription_topics: vec![SubscriptionTopic {
@@ -351,7 +997,7 @@ mod tests {
         };
 
         let sub_p3 = SubscribePacket {
-            packet_id: 03,
+            packet_id: 3,
             subscription_identifier: None,
             user_properties: Vec::new(),
             subscription_topics: vec![SubscriptionTopic {
@@ -363,14 +1009,2579 @@ mod tests {
             }],
         };
 
-        let r1 = broker.accept_sub(sub_p1);
-        assert_eq!(r1.packet_id, 01);
+        let r1 = broker.accept_sub("1004", sub_p1);
+        assert_eq!(r1.packet_id, 1);
+
+        let r2 = broker.accept_sub("1004", sub_p2);
+        assert_eq!(r2.packet_id, 2);
+
+        let r3 = broker.accept_sub("1004", sub_p3);
+        assert_eq!(r3.packet_id, 3);
+    }
+
+    #[test]
+    fn test_publish_routed_to_subscriber() {
+        let mut broker = MBroker::new();
+        let sub_p = SubscribePacket {
+            packet_id: 1,
+            subscription_identifier: None,
+            user_properties: Vec::new(),
+            subscription_topics: vec![SubscriptionTopic {
+                topic_filter: "gwu".parse().unwrap(),
+                maximum_qos: QoS::AtLeastOnce,
+                no_local: false,
+                retain_as_published: false,
+                retain_handling: RetainHandling::SendAtSubscribeTime,
+            }],
+        };
+        broker.accept_sub("1004", sub_p);
+
+        // publish to the subscribed topic
+        let pub_p = PublishPacket {
+            is_duplicate: false,
+            qos: QoS::AtLeastOnce,
+            retain: true,
+            topic: "gwu".parse().unwrap(),
+            user_properties: Vec::new(),
+            payload: Bytes::from("this is gwu"),
+            packet_id: Some(42),
+            payload_format_indicator: None,
+            message_expiry_interval: None,
+            topic_alias: None,
+            response_topic: None,
+            correlation_data: None,
+            subscription_identifier: None,
+            content_type: None,
+        };
+
+        let (deliveries, ack) = broker.accept_publish("1005", pub_p);
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].0, "1004");
+        assert_eq!(deliveries[0].1.payload, Bytes::from("this is gwu"));
+        assert!(!deliveries[0].1.retain);
+
+        match ack {
+            Some(Packet::PublishAck(ack)) => {
+                assert_eq!(ack.packet_id, 42);
+                assert_eq!(ack.reason_code, PublishAckReason::Success);
+            },
+            ack => panic!("Expected PUBACK, got {:?}", ack),
+        };
+    }
+
+    #[test]
+    fn test_publish_no_subscribers() {
+        let mut broker = MBroker::new();
+        let pub_p = PublishPacket {
+            is_duplicate: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic: "udel".parse().unwrap(),
+            user_properties: Vec::new(),
+            payload: Bytes::from("this is udel"),
+            packet_id: Some(7),
+            payload_format_indicator: None,
+            message_expiry_interval: None,
+            topic_alias: None,
+            response_topic: None,
+            correlation_data: None,
+            subscription_identifier: None,
+            content_type: None,
+        };
+
+        let (deliveries, ack) = broker.accept_publish("1005", pub_p);
+        assert!(deliveries.is_empty());
+        match ack {
+            Some(Packet::PublishAck(ack)) => assert_eq!(ack.reason_code, PublishAckReason::NoMatchingSubscribers),
+            ack => panic!("Expected PUBACK, got {:?}", ack),
+        };
+    }
+
+    // (filter, topic, should match)
+    const TOPIC_MATCH_CASES: [(&str, &str, bool); 39] = [
+        // exact topics
+        ("gwu", "gwu", true),
+        ("gwu", "udel", false),
+        ("gwu/seas", "gwu/seas", true),
+        ("gwu/seas", "gwu", false),
+        ("gwu", "gwu/seas", false),
+        ("/gwu", "/gwu", true),
+        ("/gwu", "gwu", false),
+        // single level wildcard
+        ("+", "gwu", true),
+        ("+", "gwu/seas", false),
+        ("+", "/gwu", false),
+        ("+/+", "/gwu", true),
+        ("gwu/+", "gwu/seas", true),
+        ("gwu/+", "gwu/ccas", true),
+        ("gwu/+", "gwu", false),
+        ("gwu/+", "gwu/seas/cs", false),
+        ("gwu/+/cs", "gwu/seas/cs", true),
+        ("gwu/+/cs", "gwu/seas/ece", false),
+        ("+/seas/+", "gwu/seas/cs", true),
+        ("gwu/+", "gwu/", true),
+        // multi level wildcard
+        ("#", "gwu", true),
+        ("#", "gwu/seas/cs", true),
+        ("#", "/gwu", true),
+        ("gwu/#", "gwu", true),
+        ("gwu/#", "gwu/seas", true),
+        ("gwu/#", "gwu/seas/cs", true),
+        ("gwu/#", "udel/seas", false),
+        ("gwu/seas/#", "gwu", false),
+        ("gwu/+/#", "gwu/seas", true),
+        ("gwu/+/#", "gwu/seas/cs/lab", true),
+        ("gwu/+/#", "gwu", false),
+        ("+/#", "gwu", true),
+        // '$' topics are not matched by wildcards at the first level
+        ("#", "$SYS/stats", false),
+        ("+/stats", "$SYS/stats", false),
+        ("+/#", "$SYS/stats", false),
+        ("$SYS/#", "$SYS/stats", true),
+        ("$SYS/+", "$SYS/stats", true),
+        ("$SYS/stats", "$SYS/stats", true),
+        ("gwu/+", "gwu/$seas", true),
+        ("gwu/#", "gwu/$seas", true),
+    ];
+
+    #[test]
+    fn test_topic_matching() {
+        for (filter, topic, should_match) in TOPIC_MATCH_CASES {
+            let mut tree = SubscriptionTree::new();
+            tree.insert(&filter.parse().unwrap(), filter);
+
+            let matches: Vec<&&str> = tree.matching_subscribers(&topic.parse().unwrap()).collect();
+            assert_eq!(
+                !matches.is_empty(),
+                should_match,
+                "filter '{}' against topic '{}'",
+                filter,
+                topic
+            );
+        }
+    }
+
+    #[test]
+    fn test_retained_topic_matching() {
+        // stored topics are matched one at a time against the filter
+        for (filter, topic, should_match) in TOPIC_MATCH_CASES {
+            assert_eq!(
+                topic_matches(&filter.parse().unwrap(), &topic.parse().unwrap()),
+                should_match,
+                "filter '{}' against topic '{}'",
+                filter,
+                topic
+            );
+        }
+    }
+
+    #[test]
+    fn test_topic_matching_multiple_subscribers() {
+        let mut tree = SubscriptionTree::new();
+        for filter in ["gwu/seas", "gwu/+", "gwu/#", "#", "+/ccas", "udel/#"] {
+            tree.insert(&filter.parse().unwrap(), filter);
+        }
+
+        let mut matches: Vec<&str> =
+            tree.matching_subscribers(&"gwu/seas".parse().unwrap()).copied().collect();
+        matches.sort_unstable();
+        assert_eq!(matches, vec!["#", "gwu/#", "gwu/+", "gwu/seas"]);
+
+        let mut matches: Vec<&str> =
+            tree.matching_subscribers(&"gwu".parse().unwrap()).copied().collect();
+        matches.sort_unstable();
+        assert_eq!(matches, vec!["#", "gwu/#"]);
+    }
+
+    fn encoded_publish(topic: &str, payload: Bytes) -> BytesMut {
+        let packet = Packet::Publish(PublishPacket {
+            is_duplicate: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic: topic.parse().unwrap(),
+            user_properties: Vec::new(),
+            payload,
+            packet_id: Some(1),
+            payload_format_indicator: None,
+            message_expiry_interval: None,
+            topic_alias: None,
+            response_topic: None,
+            correlation_data: None,
+            subscription_identifier: None,
+            content_type: None,
+        });
+        let mut buf = BytesMut::new();
+        cm_encode(packet, &mut buf).unwrap();
+        buf
+    }
 
-        let r2 = broker.accept_sub(sub_p2);
-        assert_eq!(r2.packet_id, 02);
+    #[test]
+    fn test_decoder_split_packet() {
+        let buf = encoded_publish("gwu", Bytes::from("this is gwu"));
+        let mut decoder = StreamDecoder::new();
+
+        // feed one byte at a time; nothing comes out until the last byte
+        for byte in &buf[..buf.len() - 1] {
+            decoder.feed(&[*byte]);
+            assert!(decoder.next_packet().unwrap().is_none());
+        }
+        decoder.feed(&buf[buf.len() - 1..]);
+
+        match decoder.next_packet() {
+            Ok(Some(Packet::Publish(p))) => assert_eq!(p.payload, Bytes::from("this is gwu")),
+            _ => panic!("Incorrect type returned"),
+        };
+        assert!(decoder.next_packet().unwrap().is_none());
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn test_decoder_multiple_packets() {
+        let mut buf = encoded_publish("gwu", Bytes::from("first"));
+        buf.extend_from_slice(&encoded_publish("udel", Bytes::from("second")));
+        let third = encoded_publish("uwm", Bytes::from("third"));
+        // the third packet is only partly here
+        buf.extend_from_slice(&third[..3]);
+
+        let mut decoder = StreamDecoder::new();
+        decoder.feed(&buf);
+
+        let mut topics = Vec::new();
+        while let Some(packet) = decoder.next_packet().unwrap() {
+            match packet {
+                Packet::Publish(p) => topics.push(p.topic.to_string()),
+                _ => panic!("Incorrect type returned"),
+            }
+        }
+        assert_eq!(topics, vec!["gwu", "udel"]);
+
+        decoder.feed(&third[3..]);
+        match decoder.next_packet() {
+            Ok(Some(Packet::Publish(p))) => assert_eq!(p.topic.to_string(), "uwm"),
+            _ => panic!("Incorrect type returned"),
+        };
+    }
+
+    #[test]
+    fn test_decoder_large_packet() {
+        let payload = Bytes::from(vec![b'g'; 5000]);
+        let buf = encoded_publish("gwu", payload.clone());
+        assert!(buf.len() > 512);
+
+        // arrives in 512 byte reads like it would from the socket
+        let mut decoder = StreamDecoder::new();
+        let mut decoded = None;
+        for chunk in buf.chunks(512) {
+            decoder.feed(chunk);
+            if let Some(packet) = decoder.next_packet().unwrap() {
+                decoded = Some(packet);
+            }
+        }
+
+        match decoded {
+            Some(Packet::Publish(p)) => assert_eq!(p.payload, payload),
+            _ => panic!("Incorrect type returned"),
+        };
+    }
+
+    #[test]
+    fn test_decoder_bad_remaining_length() {
+        let mut decoder = StreamDecoder::new();
+        decoder.feed(&[0x30, 0xff, 0xff, 0xff, 0xff, 0x01]);
+        assert_eq!(decoder.next_packet().unwrap_err(), ParseError::MalformedPacket);
+    }
 
-        let r3 = broker.accept_sub(sub_p3);
-        assert_eq!(r3.packet_id, 03);
+    #[test]
+    fn test_decode_incomplete() {
+        let buf = encoded_publish("gwu", Bytes::from("this is gwu"));
+        assert_eq!(cm_decode(&buf[..buf.len() - 1]).unwrap_err(), ParseError::Incomplete);
+        assert_eq!(cm_decode(&[]).unwrap_err(), ParseError::Incomplete);
+    }
+
+    #[test]
+    fn test_decode_malformed_packets() {
+        // (bytes, expected error)
+        let cases: [(&[u8], ParseError); 4] = [
+            // reserved packet type 0
+            (&[0x00, 0x00], ParseError::MalformedPacket),
+            // publish whose topic length runs past the end of the packet
+            (&[0x30, 0x03, 0x00, 0x09, b'g'], ParseError::MalformedPacket),
+            // property length that overflows the decoder
+            (&[136, 8, 19, 93, 207, 232, 189, 139, 152, 86], ParseError::MalformedPacket),
+            // connect with protocol level 3
+            (&[0x10, 0x0c, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x03, 0x02, 0x00, 0x3c, 0x00, 0x00],
+                ParseError::UnsupportedVersion),
+        ];
+
+        for (bytes, expected) in cases {
+            assert_eq!(cm_decode(bytes).unwrap_err(), expected, "decoding {:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn test_decode_old_protocol_version() {
+        // MQTT 3.1.1 connect
+        let bytes = [0x10, 0x0c, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x02, 0x00, 0x3c, 0x00, 0x00];
+        let err = cm_decode(&bytes).unwrap_err();
+        assert_eq!(err, ParseError::UnsupportedVersion);
+        assert_eq!(err.connect_reason(), ConnectReason::UnsupportedProtocolVersion);
+    }
+
+    #[test]
+    fn test_decoder_packet_too_large() {
+        let buf = encoded_publish("gwu", Bytes::from(vec![b'g'; 1000]));
+        let mut decoder = StreamDecoder::with_max_packet_size(512);
+
+        // refused as soon as the header says it's too big
+        decoder.feed(&buf[..4]);
+        let err = decoder.next_packet().unwrap_err();
+        assert_eq!(err, ParseError::PacketTooLarge);
+        assert_eq!(err.disconnect_reason(), DisconnectReason::PacketTooLarge);
+    }
+
+    // start a broker on a free port
+    fn start_broker() -> SocketAddr {
+        start_broker_with_config(BrokerConfig::default())
+    }
+
+    fn start_broker_with_config(config: BrokerConfig) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let broker = Arc::new(Mutex::new(MBroker::with_config(config)));
+        thread::spawn(move || serve(listener, broker));
+        addr
+    }
+
+    fn connect_packet(client_id: &str) -> ConnectPacket {
+        ConnectPacket {
+            protocol_name: String::from("MQTT"),
+            protocol_version: ProtocolVersion::V500,
+            clean_start: true,
+            keep_alive: 60,
+            user_properties: Vec::new(),
+            client_id: client_id.to_string(),
+            session_expiry_interval: None,
+            receive_maximum: None,
+            maximum_packet_size: None,
+            topic_alias_maximum: None,
+            request_response_information: None,
+            request_problem_information: None,
+            authentication_method: None,
+            authentication_data: None,
+            will: None,
+            user_name: None,
+            password: None,
+        }
+    }
+
+    fn write_packet(stream: &mut impl Write, packet: Packet) {
+        let mut buf = BytesMut::new();
+        cm_encode(packet, &mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+    }
+
+    // read the next packet, None if the broker closed the connection
+    fn read_packet(stream: &mut impl Read, decoder: &mut StreamDecoder) -> Option<Packet> {
+        let mut buf = [0; 1024];
+        loop {
+            if let Some(packet) = decoder.next_packet().unwrap() {
+                return Some(packet);
+            }
+            match stream.read(&mut buf) {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => decoder.feed(&buf[..n]),
+            }
+        }
+    }
+
+    fn open_client(addr: SocketAddr) -> (TcpStream, StreamDecoder) {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        (stream, StreamDecoder::new())
+    }
+
+    #[test]
+    fn test_session_loop() {
+        let addr = start_broker();
+        let (mut stream, mut decoder) = open_client(addr);
+
+        write_packet(&mut stream, Packet::Connect(connect_packet("1004")));
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::ConnectAck(p)) => assert_eq!(p.reason_code, ConnectReason::Success),
+            p => panic!("Expected CONNACK, got {:?}", p),
+        };
+
+        write_packet(&mut stream, Packet::Subscribe(SubscribePacket {
+            packet_id: 3,
+            subscription_identifier: None,
+            user_properties: Vec::new(),
+            subscription_topics: vec![SubscriptionTopic {
+                topic_filter: "gwu".parse().unwrap(),
+                maximum_qos: QoS::AtMostOnce,
+                no_local: false,
+                retain_as_published: false,
+                retain_handling: RetainHandling::SendAtSubscribeTime,
+            }],
+        }));
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::SubscribeAck(p)) => assert_eq!(p.packet_id, 3),
+            p => panic!("Expected SUBACK, got {:?}", p),
+        };
+
+        write_packet(&mut stream, Packet::PingRequest);
+        assert_eq!(read_packet(&mut stream, &mut decoder), Some(Packet::PingResponse));
+
+        // the broker closes the connection after DISCONNECT
+        write_packet(&mut stream, Packet::Disconnect(DisconnectPacket {
+            reason_code: DisconnectReason::NormalDisconnection,
+            session_expiry_interval: None,
+            reason_string: None,
+            user_properties: Vec::new(),
+            server_reference: None,
+        }));
+        assert_eq!(read_packet(&mut stream, &mut decoder), None);
+    }
+
+    #[test]
+    fn test_first_packet_must_be_connect() {
+        let addr = start_broker();
+        let (mut stream, mut decoder) = open_client(addr);
+
+        write_packet(&mut stream, Packet::PingRequest);
+        assert_eq!(read_packet(&mut stream, &mut decoder), None);
+    }
+
+    #[test]
+    fn test_second_connect_closes_connection() {
+        let addr = start_broker();
+        let (mut stream, mut decoder) = open_client(addr);
+
+        write_packet(&mut stream, Packet::Connect(connect_packet("1004")));
+        assert!(matches!(read_packet(&mut stream, &mut decoder), Some(Packet::ConnectAck(_))));
+
+        write_packet(&mut stream, Packet::Connect(connect_packet("1004")));
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::Disconnect(p)) => assert_eq!(p.reason_code, DisconnectReason::ProtocolError),
+            p => panic!("Expected DISCONNECT, got {:?}", p),
+        };
+        assert_eq!(read_packet(&mut stream, &mut decoder), None);
+    }
+
+    #[test]
+    fn test_deliver_to_outbound_queue() {
+        let mut broker = MBroker::new();
+        let (tx, rx) = mpsc::channel();
+        let conn_id = broker.register_outbound("1004", tx);
+
+        let pub_p = PublishPacket {
+            is_duplicate: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic: "gwu".parse().unwrap(),
+            user_properties: Vec::new(),
+            payload: Bytes::from("this is gwu"),
+            packet_id: None,
+            payload_format_indicator: None,
+            message_expiry_interval: None,
+            topic_alias: None,
+            response_topic: None,
+            correlation_data: None,
+            subscription_identifier: None,
+            content_type: None,
+        };
+
+        // only the registered client gets a message
+        broker.deliver(vec![("1004".to_string(), pub_p.clone()), ("1005".to_string(), pub_p.clone())]);
+        assert_eq!(rx.try_recv().unwrap(), Packet::Publish(pub_p.clone()));
+        assert!(rx.try_recv().is_err());
+
+        // nothing is queued once the client's connection is gone
+        broker.remove_outbound("1004", conn_id);
+        broker.deliver(vec![("1004".to_string(), pub_p)]);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_unsub() {
+        let mut broker = MBroker::new();
+        let sub_p = SubscribePacket {
+            packet_id: 1,
+            subscription_identifier: None,
+            user_properties: Vec::new(),
+            subscription_topics: vec![
+                SubscriptionTopic {
+                    topic_filter: "gwu".parse().unwrap(),
+                    maximum_qos: QoS::AtMostOnce,
+                    no_local: false,
+                    retain_as_published: false,
+                    retain_handling: RetainHandling::SendAtSubscribeTime,
+                },
+                SubscriptionTopic {
+                    topic_filter: "gwu/#".parse().unwrap(),
+                    maximum_qos: QoS::AtMostOnce,
+                    no_local: false,
+                    retain_as_published: false,
+                    retain_handling: RetainHandling::SendAtSubscribeTime,
+                },
+            ],
+        };
+        broker.accept_sub("1004", sub_p);
+
+        let pub_p = PublishPacket {
+            is_duplicate: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic: "gwu".parse().unwrap(),
+            user_properties: Vec::new(),
+            payload: Bytes::from("this is gwu"),
+            packet_id: None,
+            payload_format_indicator: None,
+            message_expiry_interval: None,
+            topic_alias: None,
+            response_topic: None,
+            correlation_data: None,
+            subscription_identifier: None,
+            content_type: None,
+        };
+        assert_eq!(broker.accept_publish("1005", pub_p.clone()).0.len(), 2);
+
+        let unsub_p = UnsubscribePacket {
+            packet_id: 2,
+            user_properties: Vec::new(),
+            topic_filters: vec!["gwu".parse().unwrap(), "udel".parse().unwrap()],
+        };
+        let res = broker.accept_unsub("1004", unsub_p);
+        assert_eq!(res.packet_id, 2);
+        assert_eq!(res.reason_codes, vec![UnsubscribeAckReason::Success, UnsubscribeAckReason::NoSubscriptionExisted]);
+
+        // only the 'gwu/#' subscription is left
+        assert_eq!(broker.accept_publish("1005", pub_p.clone()).0.len(), 1);
+
+        // unsubscribing twice finds nothing the second time
+        let unsub_p = UnsubscribePacket {
+            packet_id: 3,
+            user_properties: Vec::new(),
+            topic_filters: vec!["gwu/#".parse().unwrap(), "gwu/#".parse().unwrap()],
+        };
+        let res = broker.accept_unsub("1004", unsub_p);
+        assert_eq!(res.reason_codes, vec![UnsubscribeAckReason::Success, UnsubscribeAckReason::NoSubscriptionExisted]);
+        assert!(broker.accept_publish("1005", pub_p).0.is_empty());
+    }
+
+    #[test]
+    fn test_unsub_other_client() {
+        let mut broker = MBroker::new();
+        let sub_p = SubscribePacket {
+            packet_id: 1,
+            subscription_identifier: None,
+            user_properties: Vec::new(),
+            subscription_topics: vec![SubscriptionTopic {
+                topic_filter: "gwu".parse().unwrap(),
+                maximum_qos: QoS::AtMostOnce,
+                no_local: false,
+                retain_as_published: false,
+                retain_handling: RetainHandling::SendAtSubscribeTime,
+            }],
+        };
+        broker.accept_sub("1004", sub_p);
+
+        // a client can't remove someone else's subscription
+        let unsub_p = UnsubscribePacket {
+            packet_id: 2,
+            user_properties: Vec::new(),
+            topic_filters: vec!["gwu".parse().unwrap()],
+        };
+        let res = broker.accept_unsub("1005", unsub_p);
+        assert_eq!(res.reason_codes, vec![UnsubscribeAckReason::NoSubscriptionExisted]);
+    }
+
+    fn subscribe_packet(packet_id: u16, filter: &str, qos: QoS) -> SubscribePacket {
+        SubscribePacket {
+            packet_id,
+            subscription_identifier: None,
+            user_properties: Vec::new(),
+            subscription_topics: vec![SubscriptionTopic {
+                topic_filter: filter.parse().unwrap(),
+                maximum_qos: qos,
+                no_local: false,
+                retain_as_published: false,
+                retain_handling: RetainHandling::SendAtSubscribeTime,
+            }],
+        }
+    }
+
+    fn publish_packet(topic: &str, payload: &str, qos: QoS, packet_id: Option<u16>) -> PublishPacket {
+        PublishPacket {
+            is_duplicate: false,
+            qos,
+            retain: false,
+            topic: topic.parse().unwrap(),
+            user_properties: Vec::new(),
+            payload: Bytes::from(payload.to_string()),
+            packet_id,
+            payload_format_indicator: None,
+            message_expiry_interval: None,
+            topic_alias: None,
+            response_topic: None,
+            correlation_data: None,
+            subscription_identifier: None,
+            content_type: None,
+        }
+    }
+
+    #[test]
+    fn test_sub_records_client_id() {
+        let mut broker = MBroker::new();
+        let mut sub_p = subscribe_packet(7, "gwu/+", QoS::AtLeastOnce);
+        sub_p.subscription_identifier = Some(SubscriptionIdentifier(VariableByteInt(12)));
+        broker.accept_sub("1004", sub_p);
+        broker.accept_sub("1005", subscribe_packet(7, "gwu/seas", QoS::AtMostOnce));
+
+        let (deliveries, _) = broker.accept_publish("1005", publish_packet("gwu/seas", "hello", QoS::AtMostOnce, None));
+        let mut clients: Vec<&str> = deliveries.iter().map(|(id, _)| id.as_str()).collect();
+        clients.sort_unstable();
+        assert_eq!(clients, vec!["1004", "1005"]);
+
+        // the subscription identifier goes out with the message
+        for (client_id, packet) in &deliveries {
+            match client_id.as_str() {
+                "1004" => assert_eq!(packet.subscription_identifier, Some(SubscriptionIdentifier(VariableByteInt(12)))),
+                _ => assert_eq!(packet.subscription_identifier, None),
+            }
+        }
+    }
+
+    #[test]
+    fn test_resub_replaces_subscription() {
+        let mut broker = MBroker::new();
+        broker.accept_sub("1004", subscribe_packet(1, "gwu", QoS::AtMostOnce));
+        broker.accept_sub("1004", subscribe_packet(2, "gwu", QoS::AtLeastOnce));
+
+        // still only one copy of each message
+        let (deliveries, _) = broker.accept_publish("1005", publish_packet("gwu", "hello", QoS::AtMostOnce, None));
+        assert_eq!(deliveries.len(), 1);
+    }
+
+    #[test]
+    fn test_publish_reaches_other_connection() {
+        let addr = start_broker();
+        let (mut sub_stream, mut sub_decoder) = open_client(addr);
+        let (mut pub_stream, mut pub_decoder) = open_client(addr);
+
+        write_packet(&mut sub_stream, Packet::Connect(connect_packet("1004")));
+        assert!(matches!(read_packet(&mut sub_stream, &mut sub_decoder), Some(Packet::ConnectAck(_))));
+        write_packet(&mut sub_stream, Packet::Subscribe(subscribe_packet(1, "gwu/#", QoS::AtMostOnce)));
+        assert!(matches!(read_packet(&mut sub_stream, &mut sub_decoder), Some(Packet::SubscribeAck(_))));
+
+        write_packet(&mut pub_stream, Packet::Connect(connect_packet("1005")));
+        assert!(matches!(read_packet(&mut pub_stream, &mut pub_decoder), Some(Packet::ConnectAck(_))));
+        write_packet(&mut pub_stream, Packet::Publish(publish_packet("gwu/seas", "this is seas", QoS::AtMostOnce, None)));
+
+        match read_packet(&mut sub_stream, &mut sub_decoder) {
+            Some(Packet::Publish(p)) => {
+                assert_eq!(p.topic.to_string(), "gwu/seas");
+                assert_eq!(p.payload, Bytes::from("this is seas"));
+            },
+            p => panic!("Expected PUBLISH, got {:?}", p),
+        };
+    }
+
+    #[test]
+    fn test_suback_reason_per_filter() {
+        let mut broker = MBroker::with_config(BrokerConfig { maximum_qos: QoS::AtLeastOnce, ..BrokerConfig::default() });
+        let mut sub_p = subscribe_packet(5, "gwu", QoS::ExactlyOnce);
+        sub_p.subscription_topics.extend(subscribe_packet(5, "udel/#", QoS::AtMostOnce).subscription_topics);
+        sub_p.subscription_topics.extend(subscribe_packet(5, "$share/labs/gwu", QoS::AtLeastOnce).subscription_topics);
+        sub_p.subscription_topics.extend(subscribe_packet(5, "uwm/+", QoS::AtLeastOnce).subscription_topics);
+
+        let res = broker.accept_sub("1004", sub_p);
+        assert_eq!(res.packet_id, 5);
+        assert_eq!(res.reason_codes, vec![
+            // capped at the broker's maximum
+            SubscribeAckReason::GrantedQoSOne,
+            SubscribeAckReason::GrantedQoSZero,
+            SubscribeAckReason::SharedSubscriptionsNotSupported,
+            SubscribeAckReason::GrantedQoSOne,
+        ]);
+
+        // the shared subscription wasn't stored
+        let (deliveries, _) = broker.accept_publish("1005", publish_packet("gwu", "hello", QoS::AtMostOnce, None));
+        assert_eq!(deliveries.len(), 1);
+    }
+
+    #[test]
+    fn test_suback_grants_requested_qos() {
+        let mut broker = MBroker::new();
+        for (qos, reason) in [
+            (QoS::AtMostOnce, SubscribeAckReason::GrantedQoSZero),
+            (QoS::AtLeastOnce, SubscribeAckReason::GrantedQoSOne),
+            (QoS::ExactlyOnce, SubscribeAckReason::GrantedQoSTwo),
+        ] {
+            let res = broker.accept_sub("1004", subscribe_packet(1, "gwu", qos));
+            assert_eq!(res.reason_codes, vec![reason]);
+        }
+    }
+
+    #[test]
+    fn test_publish_above_maximum_qos() {
+        let mut broker = MBroker::with_config(BrokerConfig { maximum_qos: QoS::AtLeastOnce, ..BrokerConfig::default() });
+        broker.accept_new_client(connect_packet("1004"));
+        broker.accept_sub("1004", subscribe_packet(1, "gwu", QoS::AtLeastOnce));
+
+        let (deliveries, reply) = broker.accept_publish("1004", publish_packet("gwu", "hello", QoS::ExactlyOnce, Some(7)));
+        assert!(deliveries.is_empty());
+        match reply {
+            Some(Packet::Disconnect(p)) => assert_eq!(p.reason_code, DisconnectReason::QosNotSupported),
+            p => panic!("Expected DISCONNECT, got {:?}", p),
+        }
+
+        // the connection sends the DISCONNECT and closes
+        let addr = start_broker_with_config(BrokerConfig { maximum_qos: QoS::AtMostOnce, ..BrokerConfig::default() });
+        let (mut stream, mut decoder) = open_client(addr);
+        write_packet(&mut stream, Packet::Connect(connect_packet("1005")));
+        assert!(matches!(read_packet(&mut stream, &mut decoder), Some(Packet::ConnectAck(_))));
+        write_packet(&mut stream, Packet::Publish(publish_packet("gwu", "hello", QoS::AtLeastOnce, Some(8))));
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::Disconnect(p)) => assert_eq!(p.reason_code, DisconnectReason::QosNotSupported),
+            p => panic!("Expected DISCONNECT, got {:?}", p),
+        }
+        assert_eq!(read_packet(&mut stream, &mut decoder), None);
+    }
+
+    #[test]
+    fn test_publish_with_topic_alias() {
+        let mut broker = MBroker::new();
+        broker.accept_new_client(connect_packet("1004"));
+        let (tx, rx) = mpsc::channel();
+        broker.register_outbound("1004", tx);
+        broker.accept_sub("1004", subscribe_packet(1, "gwu", QoS::AtMostOnce));
+
+        // the broker never offered topic aliases
+        let pub_p = PublishPacket { topic_alias: Some(TopicAlias(1)), ..publish_packet("gwu", "hello", QoS::AtMostOnce, None) };
+        let (deliveries, reply) = broker.accept_publish("1005", pub_p);
+        assert!(deliveries.is_empty());
+        match reply {
+            Some(Packet::Disconnect(p)) => assert_eq!(p.reason_code, DisconnectReason::TopicAliasInvalid),
+            p => panic!("Expected DISCONNECT, got {:?}", p),
+        }
+
+        // and the subscriber gets the full topic without an alias
+        let (deliveries, _) = broker.accept_publish("1005", publish_packet("gwu", "hello", QoS::AtMostOnce, None));
+        broker.deliver(deliveries);
+        let publish = next_publish(&rx);
+        assert_eq!(publish.topic.topic_name(), "gwu");
+        assert_eq!(publish.topic_alias, None);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_connack_advertises_maximum_qos() {
+        let mut broker = MBroker::with_config(BrokerConfig { maximum_qos: QoS::AtMostOnce, ..BrokerConfig::default() });
+        let res = broker.accept_new_client(connect_packet("1004"));
+        assert_eq!(res.maximum_qos, Some(MaximumQos(QoS::AtMostOnce)));
+
+        let mut broker = MBroker::new();
+        let res = broker.accept_new_client(connect_packet("1004"));
+        assert_eq!(res.maximum_qos, None);
+    }
+
+    fn puback_packet(packet_id: u16) -> PublishAckPacket {
+        PublishAckPacket {
+            packet_id,
+            reason_code: PublishAckReason::Success,
+            reason_string: None,
+            user_properties: Vec::new(),
+        }
+    }
+
+    // next publish waiting in a client's outbound queue
+    fn next_publish(rx: &mpsc::Receiver<Packet>) -> PublishPacket {
+        match rx.try_recv() {
+            Ok(Packet::Publish(p)) => p,
+            p => panic!("Expected PUBLISH, got {:?}", p),
+        }
+    }
+
+    #[test]
+    fn test_qos1_delivery_and_puback() {
+        let mut broker = MBroker::new();
+        broker.accept_new_client(connect_packet("1004"));
+        let (tx, rx) = mpsc::channel();
+        let conn_id = broker.register_outbound("1004", tx);
+        broker.accept_sub("1004", subscribe_packet(1, "gwu", QoS::AtLeastOnce));
+
+        let (deliveries, _) = broker.accept_publish("1005", publish_packet("gwu", "first", QoS::AtLeastOnce, Some(42)));
+        broker.deliver(deliveries);
+        let (deliveries, _) = broker.accept_publish("1005", publish_packet("gwu", "second", QoS::AtLeastOnce, Some(42)));
+        broker.deliver(deliveries);
+
+        // the broker picks its own packet ids for the subscriber
+        let first = next_publish(&rx);
+        let second = next_publish(&rx);
+        assert_eq!(first.qos, QoS::AtLeastOnce);
+        assert!(first.packet_id.is_some());
+        assert_ne!(first.packet_id, second.packet_id);
+
+        // acknowledged messages aren't sent again on reconnect
+        broker.accept_puback("1004", puback_packet(first.packet_id.unwrap()));
+        broker.remove_outbound("1004", conn_id);
+        let (tx, rx) = mpsc::channel();
+        broker.register_outbound("1004", tx);
+
+        let resent = next_publish(&rx);
+        assert_eq!(resent.payload, Bytes::from("second"));
+        assert_eq!(resent.packet_id, second.packet_id);
+        assert!(resent.is_duplicate);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_qos_downgraded_to_subscription() {
+        let mut broker = MBroker::new();
+        broker.accept_sub("1004", subscribe_packet(1, "gwu", QoS::AtMostOnce));
+
+        let (deliveries, _) = broker.accept_publish("1005", publish_packet("gwu", "hello", QoS::AtLeastOnce, Some(1)));
+        assert_eq!(deliveries[0].1.qos, QoS::AtMostOnce);
+    }
+
+    #[test]
+    fn test_qos1_inflight_window() {
+        let mut broker = MBroker::new();
+        let mut conn_p = connect_packet("1004");
+        conn_p.receive_maximum = Some(ReceiveMaximum(2));
+        broker.accept_new_client(conn_p);
+        let (tx, rx) = mpsc::channel();
+        broker.register_outbound("1004", tx);
+        broker.accept_sub("1004", subscribe_packet(1, "gwu", QoS::AtLeastOnce));
+
+        for payload in ["one", "two", "three"] {
+            let (deliveries, _) = broker.accept_publish("1005", publish_packet("gwu", payload, QoS::AtLeastOnce, Some(1)));
+            broker.deliver(deliveries);
+        }
+
+        // only two go out until one is acknowledged
+        let one = next_publish(&rx);
+        next_publish(&rx);
+        assert!(rx.try_recv().is_err());
+
+        broker.accept_puback("1004", puback_packet(one.packet_id.unwrap()));
+        assert_eq!(next_publish(&rx).payload, Bytes::from("three"));
+    }
+
+    #[test]
+    fn test_qos1_queued_while_disconnected() {
+        let mut broker = MBroker::new();
+        broker.accept_new_client(connect_packet("1004"));
+        broker.accept_sub("1004", subscribe_packet(1, "gwu", QoS::AtLeastOnce));
+
+        let (deliveries, _) = broker.accept_publish("1005", publish_packet("gwu", "while away", QoS::AtLeastOnce, Some(1)));
+        broker.deliver(deliveries);
+
+        // never sent before, so it isn't a duplicate
+        let (tx, rx) = mpsc::channel();
+        broker.register_outbound("1004", tx);
+        let packet = next_publish(&rx);
+        assert_eq!(packet.payload, Bytes::from("while away"));
+        assert!(!packet.is_duplicate);
+    }
+
+    #[test]
+    fn test_qos1_end_to_end() {
+        let addr = start_broker();
+        let (mut sub_stream, mut sub_decoder) = open_client(addr);
+        let (mut pub_stream, mut pub_decoder) = open_client(addr);
+
+        write_packet(&mut sub_stream, Packet::Connect(connect_packet("1004")));
+        assert!(matches!(read_packet(&mut sub_stream, &mut sub_decoder), Some(Packet::ConnectAck(_))));
+        write_packet(&mut sub_stream, Packet::Subscribe(subscribe_packet(1, "gwu", QoS::AtLeastOnce)));
+        assert!(matches!(read_packet(&mut sub_stream, &mut sub_decoder), Some(Packet::SubscribeAck(_))));
+
+        write_packet(&mut pub_stream, Packet::Connect(connect_packet("1005")));
+        assert!(matches!(read_packet(&mut pub_stream, &mut pub_decoder), Some(Packet::ConnectAck(_))));
+        write_packet(&mut pub_stream, Packet::Publish(publish_packet("gwu", "this is gwu", QoS::AtLeastOnce, Some(9))));
+
+        match read_packet(&mut pub_stream, &mut pub_decoder) {
+            Some(Packet::PublishAck(p)) => {
+                assert_eq!(p.packet_id, 9);
+                assert_eq!(p.reason_code, PublishAckReason::Success);
+            },
+            p => panic!("Expected PUBACK, got {:?}", p),
+        };
+
+        match read_packet(&mut sub_stream, &mut sub_decoder) {
+            Some(Packet::Publish(p)) => {
+                assert_eq!(p.qos, QoS::AtLeastOnce);
+                write_packet(&mut sub_stream, Packet::PublishAck(puback_packet(p.packet_id.unwrap())));
+            },
+            p => panic!("Expected PUBLISH, got {:?}", p),
+        };
+    }
+
+    #[test]
+    fn test_qos2_inbound_deduplicated() {
+        let mut broker = MBroker::new();
+        broker.accept_new_client(connect_packet("1005"));
+        broker.accept_sub("1004", subscribe_packet(1, "billing", QoS::ExactlyOnce));
+
+        let pub_p = publish_packet("billing", "42 kWh", QoS::ExactlyOnce, Some(7));
+        let (deliveries, ack) = broker.accept_publish("1005", pub_p.clone());
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].1.qos, QoS::ExactlyOnce);
+        match ack {
+            Some(Packet::PublishReceived(p)) => {
+                assert_eq!(p.packet_id, 7);
+                assert_eq!(p.reason_code, PublishReceivedReason::Success);
+            },
+            ack => panic!("Expected PUBREC, got {:?}", ack),
+        };
+
+        // the client didn't see the PUBREC and sent the message again
+        let mut dup = pub_p.clone();
+        dup.is_duplicate = true;
+        let (deliveries, ack) = broker.accept_publish("1005", dup);
+        assert!(deliveries.is_empty());
+        assert!(matches!(ack, Some(Packet::PublishReceived(_))));
+
+        let pub_comp = broker.accept_pubrel("1005", PublishReleasePacket {
+            packet_id: 7,
+            reason_code: PublishReleaseReason::Success,
+            reason_string: None,
+            user_properties: Vec::new(),
+        });
+        assert_eq!(pub_comp.packet_id, 7);
+        assert_eq!(pub_comp.reason_code, PublishCompleteReason::Success);
+
+        // once released the packet id can carry a new message
+        let (deliveries, _) = broker.accept_publish("1005", pub_p);
+        assert_eq!(deliveries.len(), 1);
+    }
+
+    #[test]
+    fn test_qos2_unknown_pubrel() {
+        let mut broker = MBroker::new();
+        broker.accept_new_client(connect_packet("1005"));
+        let pub_comp = broker.accept_pubrel("1005", PublishReleasePacket {
+            packet_id: 3,
+            reason_code: PublishReleaseReason::Success,
+            reason_string: None,
+            user_properties: Vec::new(),
+        });
+        assert_eq!(pub_comp.reason_code, PublishCompleteReason::PacketIdentifierNotFound);
+    }
+
+    #[test]
+    fn test_qos2_outbound_handshake() {
+        let mut broker = MBroker::new();
+        broker.accept_new_client(connect_packet("1004"));
+        let (tx, rx) = mpsc::channel();
+        let conn_id = broker.register_outbound("1004", tx);
+        broker.accept_sub("1004", subscribe_packet(1, "billing", QoS::ExactlyOnce));
+
+        let (deliveries, _) = broker.accept_publish("1005", publish_packet("billing", "42 kWh", QoS::ExactlyOnce, Some(7)));
+        broker.deliver(deliveries);
+        let packet = next_publish(&rx);
+        assert_eq!(packet.qos, QoS::ExactlyOnce);
+        let packet_id = packet.packet_id.unwrap();
+
+        // PUBREC is answered with PUBREL
+        broker.accept_pubrec("1004", PublishReceivedPacket {
+            packet_id,
+            reason_code: PublishReceivedReason::Success,
+            reason_string: None,
+            user_properties: Vec::new(),
+        });
+        match rx.try_recv() {
+            Ok(Packet::PublishRelease(p)) => assert_eq!(p.packet_id, packet_id),
+            p => panic!("Expected PUBREL, got {:?}", p),
+        };
+
+        // after a reconnect the PUBREL is sent again, not the message
+        broker.remove_outbound("1004", conn_id);
+        let (tx, rx) = mpsc::channel();
+        let conn_id = broker.register_outbound("1004", tx);
+        match rx.try_recv() {
+            Ok(Packet::PublishRelease(p)) => assert_eq!(p.packet_id, packet_id),
+            p => panic!("Expected PUBREL, got {:?}", p),
+        };
+
+        // PUBCOMP finishes the exchange
+        broker.accept_pubcomp("1004", PublishCompletePacket {
+            packet_id,
+            reason_code: PublishCompleteReason::Success,
+            reason_string: None,
+            user_properties: Vec::new(),
+        });
+        broker.remove_outbound("1004", conn_id);
+        let (tx, rx) = mpsc::channel();
+        broker.register_outbound("1004", tx);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_qos2_end_to_end() {
+        let addr = start_broker();
+        let (mut sub_stream, mut sub_decoder) = open_client(addr);
+        let (mut pub_stream, mut pub_decoder) = open_client(addr);
+
+        write_packet(&mut sub_stream, Packet::Connect(connect_packet("1004")));
+        assert!(matches!(read_packet(&mut sub_stream, &mut sub_decoder), Some(Packet::ConnectAck(_))));
+        write_packet(&mut sub_stream, Packet::Subscribe(subscribe_packet(1, "billing", QoS::ExactlyOnce)));
+        assert!(matches!(read_packet(&mut sub_stream, &mut sub_decoder), Some(Packet::SubscribeAck(_))));
+
+        write_packet(&mut pub_stream, Packet::Connect(connect_packet("1005")));
+        assert!(matches!(read_packet(&mut pub_stream, &mut pub_decoder), Some(Packet::ConnectAck(_))));
+
+        // publisher side: PUBLISH, PUBREC, PUBREL, PUBCOMP
+        let pub_p = publish_packet("billing", "42 kWh", QoS::ExactlyOnce, Some(11));
+        write_packet(&mut pub_stream, Packet::Publish(pub_p.clone()));
+        assert!(matches!(read_packet(&mut pub_stream, &mut pub_decoder), Some(Packet::PublishReceived(_))));
+        // a resend before PUBREL must not be delivered twice
+        write_packet(&mut pub_stream, Packet::Publish(PublishPacket { is_duplicate: true, ..pub_p }));
+        assert!(matches!(read_packet(&mut pub_stream, &mut pub_decoder), Some(Packet::PublishReceived(_))));
+        write_packet(&mut pub_stream, Packet::PublishRelease(PublishReleasePacket {
+            packet_id: 11,
+            reason_code: PublishReleaseReason::Success,
+            reason_string: None,
+            user_properties: Vec::new(),
+        }));
+        match read_packet(&mut pub_stream, &mut pub_decoder) {
+            Some(Packet::PublishComplete(p)) => assert_eq!(p.reason_code, PublishCompleteReason::Success),
+            p => panic!("Expected PUBCOMP, got {:?}", p),
+        };
+
+        // subscriber side: PUBLISH, PUBREC, PUBREL, PUBCOMP
+        let packet_id = match read_packet(&mut sub_stream, &mut sub_decoder) {
+            Some(Packet::Publish(p)) => p.packet_id.unwrap(),
+            p => panic!("Expected PUBLISH, got {:?}", p),
+        };
+        write_packet(&mut sub_stream, Packet::PublishReceived(PublishReceivedPacket {
+            packet_id,
+            reason_code: PublishReceivedReason::Success,
+            reason_string: None,
+            user_properties: Vec::new(),
+        }));
+        assert!(matches!(read_packet(&mut sub_stream, &mut sub_decoder), Some(Packet::PublishRelease(_))));
+        write_packet(&mut sub_stream, Packet::PublishComplete(PublishCompletePacket {
+            packet_id,
+            reason_code: PublishCompleteReason::Success,
+            reason_string: None,
+            user_properties: Vec::new(),
+        }));
+
+        // only one copy reached the subscriber
+        write_packet(&mut sub_stream, Packet::PingRequest);
+        assert_eq!(read_packet(&mut sub_stream, &mut sub_decoder), Some(Packet::PingResponse));
+    }
+
+    fn retained_packet(topic: &str, payload: &str) -> PublishPacket {
+        PublishPacket { retain: true, ..publish_packet(topic, payload, QoS::AtLeastOnce, Some(1)) }
+    }
+
+    #[test]
+    fn test_retained_store() {
+        let mut store = RetainedStore::new();
+        store.insert(retained_packet("gwu/seas", "first"));
+        store.insert(retained_packet("gwu/ccas", "ccas"));
+        store.insert(retained_packet("udel", "udel"));
+
+        // a new message replaces the old one
+        store.insert(retained_packet("gwu/seas", "second"));
+        assert_eq!(store.len(), 3);
+        let matches = store.matching(&"gwu/seas".parse().unwrap());
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].payload, Bytes::from("second"));
+
+        // wildcards are matched against the stored topics
+        assert_eq!(store.matching(&"gwu/+".parse().unwrap()).len(), 2);
+        assert_eq!(store.matching(&"#".parse().unwrap()).len(), 3);
+        assert!(store.matching(&"uwm/#".parse().unwrap()).is_empty());
+
+        // an empty payload deletes the message
+        store.insert(retained_packet("gwu/seas", ""));
+        assert_eq!(store.len(), 2);
+        assert!(store.matching(&"gwu/seas".parse().unwrap()).is_empty());
+
+        // a topic alias isn't kept with the message
+        store.insert(PublishPacket { topic_alias: Some(TopicAlias(1)), ..retained_packet("udel", "aliased") });
+        let matches = store.matching(&"udel".parse().unwrap());
+        assert_eq!(matches[0].payload, Bytes::from("aliased"));
+        assert_eq!(matches[0].topic_alias, None);
+    }
+
+    #[test]
+    fn test_retained_sent_on_subscribe() {
+        let mut broker = MBroker::new();
+        broker.accept_new_client(connect_packet("1005"));
+        broker.accept_publish("1005", retained_packet("gwu/seas", "seas"));
+        broker.accept_publish("1005", retained_packet("gwu/ccas", "ccas"));
+        // not retained, so not kept
+        broker.accept_publish("1005", publish_packet("gwu/gsehd", "gsehd", QoS::AtLeastOnce, Some(2)));
+
+        broker.accept_new_client(connect_packet("1004"));
+        let (tx, rx) = mpsc::channel();
+        broker.register_outbound("1004", tx);
+        broker.accept_sub("1004", subscribe_packet(1, "gwu/+", QoS::AtMostOnce));
+
+        let mut payloads = Vec::new();
+        while let Ok(Packet::Publish(p)) = rx.try_recv() {
+            // retained messages keep the RETAIN flag and are sent at the granted QoS
+            assert!(p.retain);
+            assert_eq!(p.qos, QoS::AtMostOnce);
+            payloads.push(p.payload);
+        }
+        payloads.sort();
+        assert_eq!(payloads, vec![Bytes::from("ccas"), Bytes::from("seas")]);
+
+        // clearing the topic means later subscribers get nothing
+        broker.accept_publish("1005", retained_packet("gwu/seas", ""));
+        broker.accept_publish("1005", retained_packet("gwu/ccas", ""));
+        broker.accept_sub("1004", subscribe_packet(2, "gwu/#", QoS::AtMostOnce));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_retained_end_to_end() {
+        let addr = start_broker();
+        let (mut pub_stream, mut pub_decoder) = open_client(addr);
+        write_packet(&mut pub_stream, Packet::Connect(connect_packet("1005")));
+        assert!(matches!(read_packet(&mut pub_stream, &mut pub_decoder), Some(Packet::ConnectAck(_))));
+        write_packet(&mut pub_stream, Packet::Publish(retained_packet("gwu", "this is gwu")));
+        assert!(matches!(read_packet(&mut pub_stream, &mut pub_decoder), Some(Packet::PublishAck(_))));
+
+        let (mut sub_stream, mut sub_decoder) = open_client(addr);
+        write_packet(&mut sub_stream, Packet::Connect(connect_packet("1004")));
+        assert!(matches!(read_packet(&mut sub_stream, &mut sub_decoder), Some(Packet::ConnectAck(_))));
+        write_packet(&mut sub_stream, Packet::Subscribe(subscribe_packet(1, "#", QoS::AtLeastOnce)));
+
+        // SUBACK comes first, then the retained message
+        assert!(matches!(read_packet(&mut sub_stream, &mut sub_decoder), Some(Packet::SubscribeAck(_))));
+        match read_packet(&mut sub_stream, &mut sub_decoder) {
+            Some(Packet::Publish(p)) => {
+                assert!(p.retain);
+                assert_eq!(p.payload, Bytes::from("this is gwu"));
+            },
+            p => panic!("Expected PUBLISH, got {:?}", p),
+        };
+    }
+
+    fn subscribe_with_options(
+        packet_id: u16,
+        filter: &str,
+        retain_handling: RetainHandling,
+        retain_as_published: bool,
+    ) -> SubscribePacket {
+        let mut packet = subscribe_packet(packet_id, filter, QoS::AtMostOnce);
+        packet.subscription_topics[0].retain_handling = retain_handling;
+        packet.subscription_topics[0].retain_as_published = retain_as_published;
+        packet
+    }
+
+    #[test]
+    fn test_retain_handling() {
+        let mut broker = MBroker::new();
+        broker.accept_new_client(connect_packet("1005"));
+        broker.accept_publish("1005", retained_packet("gwu/seas", "seas"));
+
+        broker.accept_new_client(connect_packet("1004"));
+        let (tx, rx) = mpsc::channel();
+        broker.register_outbound("1004", tx);
+
+        // 0: sent on every subscribe, including a repeat of the same filter
+        broker.accept_sub("1004", subscribe_with_options(1, "gwu/#", RetainHandling::SendAtSubscribeTime, false));
+        assert_eq!(next_publish(&rx).payload, Bytes::from("seas"));
+        broker.accept_sub("1004", subscribe_with_options(2, "gwu/#", RetainHandling::SendAtSubscribeTime, false));
+        assert_eq!(next_publish(&rx).payload, Bytes::from("seas"));
+
+        // 1: sent only when the subscription didn't exist yet
+        broker.accept_sub(
+            "1004",
+            subscribe_with_options(3, "gwu/+", RetainHandling::SendAtSubscribeTimeIfNonexistent, false),
+        );
+        assert_eq!(next_publish(&rx).payload, Bytes::from("seas"));
+        broker.accept_sub(
+            "1004",
+            subscribe_with_options(4, "gwu/+", RetainHandling::SendAtSubscribeTimeIfNonexistent, false),
+        );
+        assert!(rx.try_recv().is_err());
+
+        // 2: never sent
+        broker.accept_sub("1004", subscribe_with_options(5, "gwu/seas", RetainHandling::DoNotSend, false));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_retain_as_published() {
+        let mut broker = MBroker::new();
+        broker.accept_new_client(connect_packet("1004"));
+        let (tx, rx) = mpsc::channel();
+        broker.register_outbound("1004", tx);
+        broker.accept_sub("1004", subscribe_with_options(1, "gwu/seas", RetainHandling::DoNotSend, true));
+        broker.accept_sub("1004", subscribe_with_options(2, "gwu/+", RetainHandling::DoNotSend, false));
+
+        broker.accept_new_client(connect_packet("1005"));
+        let retained = PublishPacket { qos: QoS::AtMostOnce, packet_id: None, ..retained_packet("gwu/seas", "seas") };
+        let (deliveries, _) = broker.accept_publish("1005", retained);
+        broker.deliver(deliveries);
+
+        // the RETAIN flag is kept only for the subscription that asked for it
+        let mut flags = vec![next_publish(&rx).retain, next_publish(&rx).retain];
+        flags.sort();
+        assert_eq!(flags, vec![false, true]);
+
+        // a message published without RETAIN never gains it
+        let (deliveries, _) = broker.accept_publish("1005", publish_packet("gwu/seas", "seas", QoS::AtMostOnce, None));
+        broker.deliver(deliveries);
+        assert!(!next_publish(&rx).retain);
+        assert!(!next_publish(&rx).retain);
+    }
+
+    #[test]
+    fn test_no_local() {
+        let mut broker = MBroker::new();
+        broker.accept_new_client(connect_packet("1005"));
+        let (tx, rx) = mpsc::channel();
+        broker.register_outbound("1005", tx);
+        let mut sub_p = subscribe_packet(1, "gwu/#", QoS::AtMostOnce);
+        sub_p.subscription_topics[0].no_local = true;
+        broker.accept_sub("1005", sub_p);
+
+        broker.accept_new_client(connect_packet("1004"));
+        broker.accept_sub("1004", subscribe_packet(1, "gwu/#", QoS::AtMostOnce));
+
+        // the publisher's own no_local subscription is skipped, others still match
+        let (deliveries, _) = broker.accept_publish("1005", publish_packet("gwu/seas", "seas", QoS::AtMostOnce, None));
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].0, "1004");
+        broker.deliver(deliveries);
+        assert!(rx.try_recv().is_err());
+
+        // messages from other clients still reach it
+        let (deliveries, _) = broker.accept_publish("1004", publish_packet("gwu/seas", "seas", QoS::AtMostOnce, None));
+        broker.deliver(deliveries);
+        assert_eq!(next_publish(&rx).payload, Bytes::from("seas"));
+    }
+
+    // CONNECT that resumes an existing session, kept for expiry seconds after disconnect
+    fn resume_packet(client_id: &str, expiry: u32) -> ConnectPacket {
+        ConnectPacket {
+            clean_start: false,
+            session_expiry_interval: Some(SessionExpiryInterval(expiry)),
+            ..connect_packet(client_id)
+        }
+    }
+
+    #[test]
+    fn test_session_present() {
+        let mut broker = MBroker::new();
+        // nothing to resume the first time
+        assert!(!broker.accept_new_client(resume_packet("1004", 60)).session_present);
+        let (tx, _rx) = mpsc::channel();
+        let conn_id = broker.register_outbound("1004", tx);
+        broker.accept_sub("1004", subscribe_packet(1, "gwu", QoS::AtLeastOnce));
+        broker.remove_outbound("1004", conn_id);
+
+        // messages published while the client is away wait in the session
+        broker.accept_new_client(connect_packet("1005"));
+        let (deliveries, _) = broker.accept_publish("1005", publish_packet("gwu", "while away", QoS::AtLeastOnce, Some(1)));
+        assert_eq!(deliveries.len(), 1);
+        broker.deliver(deliveries);
+
+        // the session and its subscription are picked up again
+        assert!(broker.accept_new_client(resume_packet("1004", 60)).session_present);
+        let (tx, rx) = mpsc::channel();
+        let conn_id = broker.register_outbound("1004", tx);
+        assert_eq!(next_publish(&rx).payload, Bytes::from("while away"));
+        broker.remove_outbound("1004", conn_id);
+
+        // clean start discards the session and its subscriptions
+        assert!(!broker.accept_new_client(connect_packet("1004")).session_present);
+        let (deliveries, _) = broker.accept_publish("1005", publish_packet("gwu", "gone", QoS::AtLeastOnce, Some(2)));
+        assert!(deliveries.is_empty());
+    }
+
+    #[test]
+    fn test_session_expiry() {
+        let mut broker = MBroker::new();
+        broker.accept_new_client(resume_packet("1004", 10));
+        let (tx, _rx) = mpsc::channel();
+        let conn_id = broker.register_outbound("1004", tx);
+        broker.accept_sub("1004", subscribe_packet(1, "gwu", QoS::AtLeastOnce));
+        broker.remove_outbound("1004", conn_id);
+
+        // kept until the interval runs out
+        broker.expire_sessions(Instant::now() + Duration::from_secs(5));
+        let (deliveries, _) = broker.accept_publish("1005", publish_packet("gwu", "kept", QoS::AtMostOnce, None));
+        assert_eq!(deliveries.len(), 1);
+
+        broker.expire_sessions(Instant::now() + Duration::from_secs(11));
+        let (deliveries, _) = broker.accept_publish("1005", publish_packet("gwu", "expired", QoS::AtMostOnce, None));
+        assert!(deliveries.is_empty());
+        assert!(!broker.accept_new_client(resume_packet("1004", 10)).session_present);
+
+        // without an interval the session ends with the connection
+        let mut connect_p = resume_packet("1004", 0);
+        connect_p.session_expiry_interval = None;
+        broker.accept_new_client(connect_p);
+        let (tx, _rx) = mpsc::channel();
+        let conn_id = broker.register_outbound("1004", tx);
+        broker.remove_outbound("1004", conn_id);
+        assert!(!broker.accept_new_client(resume_packet("1004", 10)).session_present);
+
+        // u32::MAX never expires
+        broker.accept_new_client(resume_packet("1004", u32::MAX));
+        let (tx, _rx) = mpsc::channel();
+        let conn_id = broker.register_outbound("1004", tx);
+        broker.remove_outbound("1004", conn_id);
+        broker.expire_sessions(Instant::now() + Duration::from_secs(u32::MAX as u64));
+        assert!(broker.accept_new_client(resume_packet("1004", 10)).session_present);
+    }
+
+    #[test]
+    fn test_session_taken_over() {
+        let addr = start_broker();
+        let (mut old_stream, mut old_decoder) = open_client(addr);
+        write_packet(&mut old_stream, Packet::Connect(resume_packet("1004", 60)));
+        assert!(matches!(read_packet(&mut old_stream, &mut old_decoder), Some(Packet::ConnectAck(_))));
+        write_packet(&mut old_stream, Packet::Subscribe(subscribe_packet(1, "gwu", QoS::AtLeastOnce)));
+        assert!(matches!(read_packet(&mut old_stream, &mut old_decoder), Some(Packet::SubscribeAck(_))));
+
+        // the second connection with the same id resumes the session
+        let (mut new_stream, mut new_decoder) = open_client(addr);
+        write_packet(&mut new_stream, Packet::Connect(resume_packet("1004", 60)));
+        match read_packet(&mut new_stream, &mut new_decoder) {
+            Some(Packet::ConnectAck(p)) => assert!(p.session_present),
+            p => panic!("Expected CONNACK, got {:?}", p),
+        };
+
+        // and the first one is told why it's being dropped, then closed
+        match read_packet(&mut old_stream, &mut old_decoder) {
+            Some(Packet::Disconnect(p)) => assert_eq!(p.reason_code, DisconnectReason::SessionTakenOver),
+            p => panic!("Expected DISCONNECT, got {:?}", p),
+        };
+        assert!(read_packet(&mut old_stream, &mut old_decoder).is_none());
+
+        // messages for the client go to the new connection, even after the old one closed
+        let (mut pub_stream, mut pub_decoder) = open_client(addr);
+        write_packet(&mut pub_stream, Packet::Connect(connect_packet("1005")));
+        assert!(matches!(read_packet(&mut pub_stream, &mut pub_decoder), Some(Packet::ConnectAck(_))));
+        write_packet(&mut pub_stream, Packet::Publish(publish_packet("gwu", "this is gwu", QoS::AtMostOnce, None)));
+        match read_packet(&mut new_stream, &mut new_decoder) {
+            Some(Packet::Publish(p)) => assert_eq!(p.payload, Bytes::from("this is gwu")),
+            p => panic!("Expected PUBLISH, got {:?}", p),
+        };
+    }
+
+    #[test]
+    fn test_assigned_client_id() {
+        let mut broker = MBroker::new();
+        // a client that picked its own id doesn't get one back
+        let conn_ack = broker.accept_new_client(connect_packet("1004"));
+        assert_eq!(conn_ack.reason_code, ConnectReason::Success);
+        assert!(conn_ack.assigned_client_identifier.is_none());
+
+        // an empty id gets a fresh one each time
+        let first = broker.accept_new_client(connect_packet("")).assigned_client_identifier.unwrap().0;
+        let second = broker.accept_new_client(connect_packet("")).assigned_client_identifier.unwrap().0;
+        assert!(!first.is_empty());
+        assert_ne!(first, second);
+
+        // the assigned id works like any other
+        broker.accept_sub(&first, subscribe_packet(1, "gwu", QoS::AtMostOnce));
+        let (deliveries, _) = broker.accept_publish("1004", publish_packet("gwu", "this is gwu", QoS::AtMostOnce, None));
+        assert_eq!(deliveries[0].0, first);
+
+        // there's no session to resume without an id
+        let conn_ack = broker.accept_new_client(resume_packet("", 60));
+        assert_eq!(conn_ack.reason_code, ConnectReason::ClientIdentifierNotValid);
+        assert!(conn_ack.assigned_client_identifier.is_none());
+        assert!(!conn_ack.session_present);
+    }
+
+    #[test]
+    fn test_assigned_client_id_end_to_end() {
+        let addr = start_broker();
+        let (mut sub_stream, mut sub_decoder) = open_client(addr);
+        write_packet(&mut sub_stream, Packet::Connect(connect_packet("")));
+        match read_packet(&mut sub_stream, &mut sub_decoder) {
+            Some(Packet::ConnectAck(p)) => assert!(p.assigned_client_identifier.is_some()),
+            p => panic!("Expected CONNACK, got {:?}", p),
+        };
+
+        // the connection uses the assigned id for its session
+        write_packet(&mut sub_stream, Packet::Subscribe(subscribe_packet(1, "gwu", QoS::AtMostOnce)));
+        assert!(matches!(read_packet(&mut sub_stream, &mut sub_decoder), Some(Packet::SubscribeAck(_))));
+        write_packet(&mut sub_stream, Packet::Publish(publish_packet("gwu", "this is gwu", QoS::AtMostOnce, None)));
+        assert!(matches!(read_packet(&mut sub_stream, &mut sub_decoder), Some(Packet::Publish(_))));
+
+        // an empty id without clean start is refused and the connection closed
+        let (mut stream, mut decoder) = open_client(addr);
+        write_packet(&mut stream, Packet::Connect(resume_packet("", 60)));
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::ConnectAck(p)) => assert_eq!(p.reason_code, ConnectReason::ClientIdentifierNotValid),
+            p => panic!("Expected CONNACK, got {:?}", p),
+        };
+        assert!(read_packet(&mut stream, &mut decoder).is_none());
+    }
+
+    #[test]
+    fn test_server_keep_alive() {
+        let mut broker = MBroker::new();
+        assert!(broker.accept_new_client(connect_packet("1004")).server_keep_alive.is_none());
+
+        let mut broker = MBroker::with_config(BrokerConfig { server_keep_alive: Some(30), ..BrokerConfig::default() });
+        assert_eq!(broker.accept_new_client(connect_packet("1004")).server_keep_alive.unwrap().0, 30);
+    }
+
+    #[test]
+    fn test_packet_too_large() {
+        let addr = start_broker_with_config(BrokerConfig { maximum_packet_size: 256, ..BrokerConfig::default() });
+        let (mut stream, mut decoder) = open_client(addr);
+        write_packet(&mut stream, Packet::Connect(connect_packet("1004")));
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::ConnectAck(p)) => assert_eq!(p.maximum_packet_size, Some(MaximumPacketSize(256))),
+            p => panic!("Expected CONNACK, got {:?}", p),
+        };
+
+        // a PUBLISH header claiming the largest remaining length there is,
+        // refused without waiting for the rest
+        stream.write_all(&[0x30, 0xff, 0xff, 0xff, 0x7f]).unwrap();
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::Disconnect(p)) => assert_eq!(p.reason_code, DisconnectReason::PacketTooLarge),
+            p => panic!("Expected DISCONNECT, got {:?}", p),
+        };
+        assert_eq!(read_packet(&mut stream, &mut decoder), None);
+
+        // before CONNECT the answer is a CONNACK
+        let (mut stream, mut decoder) = open_client(addr);
+        let connect_p = ConnectPacket { client_id: "1005".repeat(100), ..connect_packet("1005") };
+        write_packet(&mut stream, Packet::Connect(connect_p));
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::ConnectAck(p)) => assert_eq!(p.reason_code, ConnectReason::PacketTooLarge),
+            p => panic!("Expected CONNACK, got {:?}", p),
+        };
+    }
+
+    #[test]
+    fn test_keep_alive_timeout() {
+        let addr = start_broker();
+        let (mut stream, mut decoder) = open_client(addr);
+        write_packet(&mut stream, Packet::Connect(ConnectPacket { keep_alive: 1, ..connect_packet("1004") }));
+        assert!(matches!(read_packet(&mut stream, &mut decoder), Some(Packet::ConnectAck(_))));
+
+        // pings keep the connection open past the keep-alive
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(500));
+            write_packet(&mut stream, Packet::PingRequest);
+            assert_eq!(read_packet(&mut stream, &mut decoder), Some(Packet::PingResponse));
+        }
+
+        // silence for one and a half keep-alive periods closes it
+        let silent_since = Instant::now();
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::Disconnect(p)) => assert_eq!(p.reason_code, DisconnectReason::KeepAliveTimeout),
+            p => panic!("Expected DISCONNECT, got {:?}", p),
+        };
+        assert!(silent_since.elapsed() >= Duration::from_millis(1500));
+        // closed by the broker, not by the test's read timeout
+        assert!(silent_since.elapsed() < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_connect_timeout() {
+        let config = BrokerConfig { connect_timeout: Duration::from_secs(1), ..BrokerConfig::default() };
+        let addr = start_broker_with_config(config.clone());
+
+        // a socket that never sends CONNECT is closed without a word
+        let (mut stream, mut decoder) = open_client(addr);
+        let opened = Instant::now();
+        assert_eq!(read_packet(&mut stream, &mut decoder), None);
+        assert!(opened.elapsed() >= Duration::from_secs(1));
+        // closed by the broker, not by the test's read timeout
+        assert!(opened.elapsed() < Duration::from_secs(3));
+
+        // once connected, the client's own keep-alive applies instead
+        let (mut stream, mut decoder) = open_client(addr);
+        write_packet(&mut stream, Packet::Connect(connect_packet("1004")));
+        assert!(matches!(read_packet(&mut stream, &mut decoder), Some(Packet::ConnectAck(_))));
+        thread::sleep(Duration::from_millis(1500));
+        write_packet(&mut stream, Packet::PingRequest);
+        assert_eq!(read_packet(&mut stream, &mut decoder), Some(Packet::PingResponse));
+
+        // so does one that stalls halfway through its authentication exchange
+        let addr = scram_broker_with_config(config);
+        let (mut stream, mut decoder) = open_client(addr);
+        write_packet(&mut stream, Packet::Connect(scram_connect_packet("1005", "n,,n=alice,r=fyko+d2lbbFgONRv9qkxdawL")));
+        assert!(matches!(read_packet(&mut stream, &mut decoder), Some(Packet::Authenticate(_))));
+        let challenged = Instant::now();
+        assert_eq!(read_packet(&mut stream, &mut decoder), None);
+        assert!(challenged.elapsed() < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_server_keep_alive_overrides_client() {
+        let addr = start_broker_with_config(BrokerConfig { server_keep_alive: Some(1), ..BrokerConfig::default() });
+        let (mut stream, mut decoder) = open_client(addr);
+        // the client asked for no keep-alive at all
+        write_packet(&mut stream, Packet::Connect(ConnectPacket { keep_alive: 0, ..connect_packet("1004") }));
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::ConnectAck(p)) => assert_eq!(p.server_keep_alive.unwrap().0, 1),
+            p => panic!("Expected CONNACK, got {:?}", p),
+        };
+
+        let silent_since = Instant::now();
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::Disconnect(p)) => assert_eq!(p.reason_code, DisconnectReason::KeepAliveTimeout),
+            p => panic!("Expected DISCONNECT, got {:?}", p),
+        };
+        assert!(silent_since.elapsed() >= Duration::from_millis(1500));
+        // closed by the broker, not by the test's read timeout
+        assert!(silent_since.elapsed() < Duration::from_secs(3));
+    }
+
+    // CONNECT carrying a will for topic, held back for delay seconds
+    fn will_connect_packet(client_id: &str, topic: &str, delay: u32) -> ConnectPacket {
+        ConnectPacket {
+            will: Some(FinalWill {
+                topic: topic.to_string(),
+                payload: Bytes::from(format!("{} offline", client_id)),
+                qos: QoS::AtMostOnce,
+                should_retain: false,
+                will_delay_interval: Some(WillDelayInterval(delay)),
+                payload_format_indicator: None,
+                message_expiry_interval: None,
+                content_type: None,
+                response_topic: None,
+                correlation_data: None,
+                user_properties: Vec::new(),
+            }),
+            ..connect_packet(client_id)
+        }
+    }
+
+    fn normal_disconnect() -> DisconnectPacket {
+        DisconnectPacket {
+            reason_code: DisconnectReason::NormalDisconnection,
+            session_expiry_interval: None,
+            reason_string: None,
+            user_properties: Vec::new(),
+            server_reference: None,
+        }
+    }
+
+    // a broker with 1004 subscribed to every device's status
+    fn will_broker() -> (MBroker, mpsc::Receiver<Packet>) {
+        let mut broker = MBroker::new();
+        broker.accept_new_client(connect_packet("1004"));
+        let (tx, rx) = mpsc::channel();
+        broker.register_outbound("1004", tx);
+        broker.accept_sub("1004", subscribe_packet(1, "devices/+", QoS::AtMostOnce));
+        (broker, rx)
+    }
+
+    #[test]
+    fn test_will_published_on_drop() {
+        let (mut broker, rx) = will_broker();
+        broker.accept_new_client(will_connect_packet("1005", "devices/1005", 0));
+        let (tx, _rx) = mpsc::channel();
+        let conn_id = broker.register_outbound("1005", tx);
+
+        broker.remove_outbound("1005", conn_id);
+        let will = next_publish(&rx);
+        assert_eq!(will.topic.topic_name(), "devices/1005");
+        assert_eq!(will.payload, Bytes::from("1005 offline"));
+        // only once
+        broker.publish_due_wills(Instant::now() + Duration::from_secs(60));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_will_not_published_on_disconnect() {
+        let (mut broker, rx) = will_broker();
+        broker.accept_new_client(will_connect_packet("1005", "devices/1005", 0));
+        let (tx, _rx) = mpsc::channel();
+        let conn_id = broker.register_outbound("1005", tx);
+
+        broker.accept_disconnect("1005", normal_disconnect()).unwrap();
+        broker.remove_outbound("1005", conn_id);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_will_delay() {
+        let (mut broker, rx) = will_broker();
+        broker.accept_new_client(ConnectPacket {
+            session_expiry_interval: Some(SessionExpiryInterval(300)),
+            ..will_connect_packet("1005", "devices/1005", 10)
+        });
+        let (tx, _rx) = mpsc::channel();
+        let conn_id = broker.register_outbound("1005", tx);
+        broker.remove_outbound("1005", conn_id);
+
+        // held back for the delay
+        broker.publish_due_wills(Instant::now() + Duration::from_secs(5));
+        assert!(rx.try_recv().is_err());
+        broker.publish_due_wills(Instant::now() + Duration::from_secs(11));
+        assert_eq!(next_publish(&rx).topic.topic_name(), "devices/1005");
+
+        // reconnecting within the delay cancels it
+        broker.accept_new_client(ConnectPacket {
+            clean_start: false,
+            session_expiry_interval: Some(SessionExpiryInterval(300)),
+            ..will_connect_packet("1005", "devices/1005", 10)
+        });
+        let (tx, _rx) = mpsc::channel();
+        let conn_id = broker.register_outbound("1005", tx);
+        broker.remove_outbound("1005", conn_id);
+        broker.accept_new_client(resume_packet("1005", 300));
+        broker.publish_due_wills(Instant::now() + Duration::from_secs(11));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_will_published_when_session_ends() {
+        let (mut broker, rx) = will_broker();
+        // the session ends before the will delay does
+        broker.accept_new_client(ConnectPacket {
+            session_expiry_interval: Some(SessionExpiryInterval(5)),
+            ..will_connect_packet("1005", "devices/1005", 60)
+        });
+        let (tx, _rx) = mpsc::channel();
+        let conn_id = broker.register_outbound("1005", tx);
+        broker.remove_outbound("1005", conn_id);
+        assert!(rx.try_recv().is_err());
+
+        broker.expire_sessions(Instant::now() + Duration::from_secs(6));
+        assert_eq!(next_publish(&rx).topic.topic_name(), "devices/1005");
+    }
+
+    #[test]
+    fn test_will_published_on_clean_start_takeover() {
+        let (mut broker, rx) = will_broker();
+        broker.accept_new_client(ConnectPacket {
+            session_expiry_interval: Some(SessionExpiryInterval(300)),
+            ..will_connect_packet("1005", "devices/1005", 60)
+        });
+        let (tx, old_rx) = mpsc::channel();
+        broker.register_outbound("1005", tx);
+
+        // the new connection's clean start ends the old session, delayed will and all
+        broker.accept_new_client(connect_packet("1005"));
+        assert!(matches!(old_rx.try_recv(), Ok(Packet::Disconnect(_))));
+        assert_eq!(next_publish(&rx).topic.topic_name(), "devices/1005");
+
+        // and the new session has no will of its own to send later
+        broker.publish_due_wills(Instant::now() + Duration::from_secs(61));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_will_rejected() {
+        let mut broker = MBroker::new();
+        let conn_ack = broker.accept_new_client(will_connect_packet("1005", "devices/#", 0));
+        assert_eq!(conn_ack.reason_code, ConnectReason::TopicNameInvalid);
+
+        let mut broker = MBroker::with_config(BrokerConfig { maximum_qos: QoS::AtMostOnce, ..BrokerConfig::default() });
+        let mut connect_p = will_connect_packet("1005", "devices/1005", 0);
+        connect_p.will.as_mut().unwrap().qos = QoS::AtLeastOnce;
+        assert_eq!(broker.accept_new_client(connect_p).reason_code, ConnectReason::QosNotSupported);
+    }
+
+    #[test]
+    fn test_will_end_to_end() {
+        let addr = start_broker();
+        let (mut sub_stream, mut sub_decoder) = open_client(addr);
+        write_packet(&mut sub_stream, Packet::Connect(connect_packet("1004")));
+        assert!(matches!(read_packet(&mut sub_stream, &mut sub_decoder), Some(Packet::ConnectAck(_))));
+        write_packet(&mut sub_stream, Packet::Subscribe(subscribe_packet(1, "devices/+", QoS::AtMostOnce)));
+        assert!(matches!(read_packet(&mut sub_stream, &mut sub_decoder), Some(Packet::SubscribeAck(_))));
+
+        // the device goes away without a DISCONNECT
+        let (mut device_stream, mut device_decoder) = open_client(addr);
+        write_packet(&mut device_stream, Packet::Connect(will_connect_packet("1005", "devices/1005", 0)));
+        assert!(matches!(read_packet(&mut device_stream, &mut device_decoder), Some(Packet::ConnectAck(_))));
+        drop(device_stream);
+
+        match read_packet(&mut sub_stream, &mut sub_decoder) {
+            Some(Packet::Publish(p)) => assert_eq!(p.payload, Bytes::from("1005 offline")),
+            p => panic!("Expected PUBLISH, got {:?}", p),
+        };
+    }
+
+    #[test]
+    fn test_disconnect_with_will_message() {
+        let (mut broker, rx) = will_broker();
+        broker.accept_new_client(will_connect_packet("1005", "devices/1005", 0));
+        let (tx, _rx) = mpsc::channel();
+        let conn_id = broker.register_outbound("1005", tx);
+
+        let disconnect = DisconnectPacket { reason_code: DisconnectReason::DisconnectWithWillMessage, ..normal_disconnect() };
+        broker.accept_disconnect("1005", disconnect).unwrap();
+        broker.remove_outbound("1005", conn_id);
+        assert_eq!(next_publish(&rx).topic.topic_name(), "devices/1005");
+    }
+
+    #[test]
+    fn test_disconnect_session_expiry() {
+        let mut broker = MBroker::new();
+        broker.accept_new_client(resume_packet("1004", 10));
+        let (tx, _rx) = mpsc::channel();
+        let conn_id = broker.register_outbound("1004", tx);
+
+        // the client stretches its session on the way out
+        let disconnect = DisconnectPacket { session_expiry_interval: Some(SessionExpiryInterval(300)), ..normal_disconnect() };
+        broker.accept_disconnect("1004", disconnect).unwrap();
+        broker.remove_outbound("1004", conn_id);
+        broker.expire_sessions(Instant::now() + Duration::from_secs(60));
+        assert!(broker.accept_new_client(resume_packet("1004", 0)).session_present);
+
+        // a session that ends with the connection can't be given an expiry at DISCONNECT
+        let disconnect = DisconnectPacket { session_expiry_interval: Some(SessionExpiryInterval(300)), ..normal_disconnect() };
+        assert_eq!(broker.accept_disconnect("1004", disconnect), Err(DisconnectReason::ProtocolError));
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let mut broker = MBroker::new();
+        broker.accept_new_client(connect_packet("1004"));
+        let (tx, rx) = mpsc::channel();
+        broker.register_outbound("1004", tx);
+
+        broker.shutdown();
+        match rx.try_recv() {
+            Ok(Packet::Disconnect(p)) => assert_eq!(p.reason_code, DisconnectReason::ServerShuttingDown),
+            p => panic!("Expected DISCONNECT, got {:?}", p),
+        };
+        // connected until the connection has sent the DISCONNECT and gone
+        assert!(broker.has_connections());
+    }
+
+    #[test]
+    fn test_shut_down_drains_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let broker = Arc::new(Mutex::new(MBroker::new()));
+        let serve_broker = Arc::clone(&broker);
+        thread::spawn(move || serve(listener, serve_broker));
+
+        let (mut stream, mut decoder) = open_client(addr);
+        write_packet(&mut stream, Packet::Connect(connect_packet("1004")));
+        assert!(matches!(read_packet(&mut stream, &mut decoder), Some(Packet::ConnectAck(_))));
+        write_packet(&mut stream, Packet::Subscribe(subscribe_packet(1, "gwu", QoS::AtMostOnce)));
+        assert!(matches!(read_packet(&mut stream, &mut decoder), Some(Packet::SubscribeAck(_))));
+
+        // a message queued just before shutdown still goes out ahead of the DISCONNECT
+        {
+            let mut broker = broker.lock().unwrap();
+            let (deliveries, _) = broker.accept_publish("1005", publish_packet("gwu", "last call", QoS::AtMostOnce, None));
+            broker.deliver(deliveries);
+        }
+        shut_down(&broker);
+        assert!(!broker.lock().unwrap().has_connections());
+
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::Publish(p)) => assert_eq!(p.payload, Bytes::from("last call")),
+            p => panic!("Expected PUBLISH, got {:?}", p),
+        };
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::Disconnect(p)) => assert_eq!(p.reason_code, DisconnectReason::ServerShuttingDown),
+            p => panic!("Expected DISCONNECT, got {:?}", p),
+        };
+        assert_eq!(read_packet(&mut stream, &mut decoder), None);
+    }
+
+    #[test]
+    fn test_broker_disconnect_reasons() {
+        let addr = start_broker();
+
+        // malformed packet once connected
+        let (mut stream, mut decoder) = open_client(addr);
+        write_packet(&mut stream, Packet::Connect(connect_packet("1004")));
+        assert!(matches!(read_packet(&mut stream, &mut decoder), Some(Packet::ConnectAck(_))));
+        stream.write_all(&[0x30, 0x03, 0x00, 0x09, b'g']).unwrap();
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::Disconnect(p)) => assert_eq!(p.reason_code, DisconnectReason::MalformedPacket),
+            p => panic!("Expected DISCONNECT, got {:?}", p),
+        };
+        assert!(read_packet(&mut stream, &mut decoder).is_none());
+
+        // packets only the server sends
+        let (mut stream, mut decoder) = open_client(addr);
+        write_packet(&mut stream, Packet::Connect(connect_packet("1005")));
+        assert!(matches!(read_packet(&mut stream, &mut decoder), Some(Packet::ConnectAck(_))));
+        write_packet(&mut stream, Packet::PingResponse);
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::Disconnect(p)) => assert_eq!(p.reason_code, DisconnectReason::ProtocolError),
+            p => panic!("Expected DISCONNECT, got {:?}", p),
+        };
+
+        // before CONNECT is accepted the answer is a CONNACK
+        let (mut stream, mut decoder) = open_client(addr);
+        stream.write_all(&[0x10, 0x0c, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x02, 0x00, 0x3c, 0x00, 0x00]).unwrap();
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::ConnectAck(p)) => assert_eq!(p.reason_code, ConnectReason::UnsupportedProtocolVersion),
+            p => panic!("Expected CONNACK, got {:?}", p),
+        };
+        assert!(read_packet(&mut stream, &mut decoder).is_none());
+    }
+
+    fn password_file() -> PasswordFile {
+        let mut password_file = PasswordFile::new();
+        // few iterations keep the tests fast
+        password_file.set_password("alice", "wonderland", 10).unwrap();
+        password_file
+    }
+
+    #[test]
+    fn test_password_file_authenticate() {
+        let password_file = password_file();
+        assert_eq!(password_file.authenticate("1004", Some("alice"), Some("wonderland")), ConnectReason::Success);
+        assert_eq!(password_file.authenticate("1004", Some("alice"), Some("looking glass")), ConnectReason::BadUserNameOrPassword);
+        assert_eq!(password_file.authenticate("1004", Some("alice"), None), ConnectReason::BadUserNameOrPassword);
+        assert_eq!(password_file.authenticate("1004", Some("bob"), Some("wonderland")), ConnectReason::BadUserNameOrPassword);
+        assert_eq!(password_file.authenticate("1004", None, None), ConnectReason::NotAuthorized);
+    }
+
+    #[test]
+    fn test_password_file_round_trip() {
+        let path = std::env::temp_dir().join(format!("mbroker-passwd-{}", std::process::id()));
+        password_file().save(&path).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // the password itself is never written out
+        assert!(contents.starts_with("alice:10:"));
+        assert!(!contents.contains("wonderland"));
+
+        let loaded = PasswordFile::parse(&format!("# lab users\n\n{}", contents)).unwrap();
+        assert_eq!(loaded.authenticate("1004", Some("alice"), Some("wonderland")), ConnectReason::Success);
+
+        // same password, different salt
+        let rehashed = password_file();
+        rehashed.save(&path).unwrap();
+        assert_ne!(std::fs::read_to_string(&path).unwrap(), contents);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(PasswordFile::parse("alice:ten:c2FsdA==:aGFzaA==").is_err());
+        assert!(PasswordFile::parse("alice").is_err());
+        assert!(password_file().set_password("bad:user", "password", 10).is_err());
+    }
+
+    #[test]
+    fn test_connect_authentication() {
+        let mut broker = MBroker::new();
+        broker.set_authenticator(Box::new(password_file()));
+
+        let conn_ack = broker.accept_new_client(ConnectPacket {
+            user_name: Some("alice".to_string()),
+            password: Some("wonderland".to_string()),
+            ..connect_packet("1004")
+        });
+        assert_eq!(conn_ack.reason_code, ConnectReason::Success);
+
+        let conn_ack = broker.accept_new_client(ConnectPacket {
+            user_name: Some("alice".to_string()),
+            password: Some("guess".to_string()),
+            ..connect_packet("1005")
+        });
+        assert_eq!(conn_ack.reason_code, ConnectReason::BadUserNameOrPassword);
+        assert_eq!(broker.accept_new_client(connect_packet("1005")).reason_code, ConnectReason::NotAuthorized);
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert_eq!(parse_options(&args(&[])), Ok(Options::default()));
+        assert_eq!(
+            parse_options(&args(&["--password-file", "users.txt"])).unwrap().password_file,
+            Some("users.txt".to_string())
+        );
+        assert!(parse_options(&args(&["--password-file"])).is_err());
+        assert!(parse_options(&args(&["--verbose"])).is_err());
+    }
+
+    const ACL_RULES: &str = "
+        # devices write their own status, dashboards read everyone's
+        allow write all devices/%c/status
+        allow read user dashboard devices/+/status
+        allow readwrite all %u/#
+        deny read all lab/secret/#
+        allow read all lab/#
+        allow readwrite client 1004 billing
+    ";
+
+    #[test]
+    fn test_acl_rules() {
+        let acl = AclFile::parse(ACL_RULES).unwrap();
+        let topic = |t: &str| t.parse().unwrap();
+        let filter = |f: &str| f.parse().unwrap();
+
+        // %c is the client id
+        assert!(acl.can_access("sensor1", None, Access::Write, &topic("devices/sensor1/status")));
+        assert!(!acl.can_access("sensor1", None, Access::Write, &topic("devices/sensor2/status")));
+        assert!(!acl.can_access("sensor1", None, Access::Read, &topic("devices/sensor1/status")));
+
+        // rules for a user
+        assert!(acl.can_subscribe("1005", Some("dashboard"), &filter("devices/+/status")));
+        assert!(acl.can_subscribe("1005", Some("dashboard"), &filter("devices/sensor1/status")));
+        assert!(!acl.can_subscribe("1005", Some("dashboard"), &filter("devices/#")));
+        assert!(!acl.can_subscribe("1005", Some("alice"), &filter("devices/+/status")));
+
+        // %u is the user name, and doesn't match clients without one
+        assert!(acl.can_subscribe("1005", Some("alice"), &filter("alice/#")));
+        assert!(acl.can_access("1005", Some("alice"), Access::Write, &topic("alice/notes")));
+        assert!(!acl.can_access("1005", Some("alice"), Access::Write, &topic("bob/notes")));
+        assert!(!acl.can_access("1005", None, Access::Write, &topic("alice/notes")));
+
+        // a substitution can't sneak in a wildcard or another level
+        assert!(!acl.can_access("+", None, Access::Write, &topic("devices/sensor1/status")));
+        assert!(!acl.can_access("1005", Some("alice/#"), Access::Write, &topic("alice/notes")));
+
+        // the first matching rule decides
+        assert!(acl.can_subscribe("1005", None, &filter("lab/+")));
+        assert!(!acl.can_subscribe("1005", None, &filter("lab/secret/plans")));
+        assert!(acl.can_subscribe("1005", None, &filter("lab/#")));
+        assert!(!acl.can_access("1005", None, Access::Read, &topic("lab/secret/plans")));
+
+        // rules for a client id
+        assert!(acl.can_access("1004", None, Access::Write, &topic("billing")));
+        assert!(!acl.can_access("1005", None, Access::Write, &topic("billing")));
+
+        // nothing matches, so it's denied
+        assert!(!acl.can_subscribe("1005", None, &filter("#")));
+    }
+
+    #[test]
+    fn test_acl_parse_errors() {
+        assert!(AclFile::parse("permit read all gwu").is_err());
+        assert!(AclFile::parse("allow delete all gwu").is_err());
+        assert!(AclFile::parse("allow read user gwu").is_err());
+        assert!(AclFile::parse("allow read all gwu/#/seas").is_err());
+        assert!(AclFile::parse("# nothing but a comment").is_ok());
+    }
+
+    #[test]
+    fn test_filter_covers() {
+        let covers = |pattern: &str, filter: &str| filter_covers(&pattern.parse().unwrap(), &filter.parse().unwrap());
+        assert!(covers("gwu/#", "gwu/seas/+"));
+        assert!(covers("gwu/#", "gwu"));
+        assert!(covers("gwu/+", "gwu/+"));
+        assert!(covers("#", "gwu/seas"));
+        assert!(!covers("gwu/+", "gwu/#"));
+        assert!(!covers("gwu/seas", "gwu/+"));
+        assert!(!covers("#", "$SYS/stats"));
+    }
+
+    fn acl_broker() -> MBroker {
+        let mut broker = MBroker::new();
+        broker.set_authorizer(Box::new(AclFile::parse(ACL_RULES).unwrap()));
+        broker
+    }
+
+    #[test]
+    fn test_acl_will_published_when_session_expires() {
+        let mut broker = MBroker::new();
+        broker.set_authorizer(Box::new(AclFile::parse("allow readwrite all %u/#\nallow read all #").unwrap()));
+        broker.accept_new_client(connect_packet("1004"));
+        let (tx, rx) = mpsc::channel();
+        broker.register_outbound("1004", tx);
+        broker.accept_sub("1004", subscribe_packet(1, "alice/#", QoS::AtMostOnce));
+
+        // alice's session ends before her will's delay does
+        broker.accept_new_client(ConnectPacket {
+            user_name: Some("alice".to_string()),
+            session_expiry_interval: Some(SessionExpiryInterval(5)),
+            ..will_connect_packet("1005", "alice/status", 60)
+        });
+        let (tx, _rx) = mpsc::channel();
+        let conn_id = broker.register_outbound("1005", tx);
+        broker.remove_outbound("1005", conn_id);
+        assert!(rx.try_recv().is_err());
+
+        // the will is still published as alice
+        broker.expire_sessions(Instant::now() + Duration::from_secs(6));
+        assert_eq!(next_publish(&rx).topic.topic_name(), "alice/status");
+    }
+
+    #[test]
+    fn test_acl_subscribe() {
+        let mut broker = acl_broker();
+        broker.accept_new_client(ConnectPacket { user_name: Some("dashboard".to_string()), ..connect_packet("1005") });
+
+        let mut sub_p = subscribe_packet(1, "devices/+/status", QoS::AtMostOnce);
+        sub_p.subscription_topics.extend(subscribe_packet(1, "devices/#", QoS::AtMostOnce).subscription_topics);
+        let sub_ack = broker.accept_sub("1005", sub_p);
+        assert_eq!(sub_ack.reason_codes, vec![SubscribeAckReason::GrantedQoSZero, SubscribeAckReason::NotAuthorized]);
+
+        // the refused filter wasn't added
+        broker.accept_new_client(connect_packet("sensor1"));
+        let (deliveries, _) = broker.accept_publish("sensor1", publish_packet("devices/sensor1/status", "online", QoS::AtMostOnce, None));
+        assert_eq!(deliveries.len(), 1);
+    }
+
+    #[test]
+    fn test_acl_publish() {
+        let mut broker = acl_broker();
+        broker.accept_new_client(connect_packet("1004"));
+        broker.accept_sub("1004", subscribe_packet(1, "billing", QoS::AtLeastOnce));
+        broker.accept_new_client(connect_packet("1005"));
+
+        let (deliveries, pub_ack) = broker.accept_publish("1005", publish_packet("billing", "free", QoS::AtLeastOnce, Some(1)));
+        assert!(deliveries.is_empty());
+        match pub_ack {
+            Some(Packet::PublishAck(p)) => assert_eq!(p.reason_code, PublishAckReason::NotAuthorized),
+            p => panic!("Expected PUBACK, got {:?}", p),
+        };
+
+        let (deliveries, pub_ack) = broker.accept_publish("1005", publish_packet("billing", "free", QoS::ExactlyOnce, Some(2)));
+        assert!(deliveries.is_empty());
+        match pub_ack {
+            Some(Packet::PublishReceived(p)) => assert_eq!(p.reason_code, PublishReceivedReason::NotAuthorized),
+            p => panic!("Expected PUBREC, got {:?}", p),
+        };
+
+        // an allowed publish goes through
+        let (deliveries, _) = broker.accept_publish("1004", publish_packet("billing", "paid", QoS::AtLeastOnce, Some(3)));
+        assert_eq!(deliveries.len(), 1);
+    }
+
+    #[test]
+    fn test_acl_filters_deliveries() {
+        let mut broker = acl_broker();
+        broker.accept_new_client(connect_packet("1004"));
+        let (tx, rx) = mpsc::channel();
+        broker.register_outbound("1004", tx);
+        broker.accept_new_client(ConnectPacket { user_name: Some("lab".to_string()), ..connect_packet("1005") });
+
+        // retained messages the subscriber may not read stay behind
+        broker.accept_publish("1005", PublishPacket { qos: QoS::AtMostOnce, ..retained_packet("lab/notes", "notes") });
+        broker.accept_publish("1005", PublishPacket { qos: QoS::AtMostOnce, ..retained_packet("lab/secret/plans", "plans") });
+        broker.accept_sub("1004", subscribe_packet(1, "lab/#", QoS::AtMostOnce));
+        assert_eq!(next_publish(&rx).payload, Bytes::from("notes"));
+        assert!(rx.try_recv().is_err());
+
+        // and so do new ones
+        let (deliveries, _) = broker.accept_publish("1005", publish_packet("lab/secret/plans", "plans", QoS::AtMostOnce, None));
+        assert!(deliveries.is_empty());
+        let (deliveries, _) = broker.accept_publish("1005", publish_packet("lab/notes", "notes", QoS::AtMostOnce, None));
+        assert_eq!(deliveries.len(), 1);
+    }
+
+
+    fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        mac.update(message);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    // the client side of SCRAM-SHA-256: the client-final-message answering server_first,
+    // and the server-final-message the client expects back
+    fn scram_client_final(password: &str, client_first_bare: &str, server_first: &str) -> (String, String) {
+        let attributes: Vec<&str> = server_first.split(',').collect();
+        let nonce = attributes[0].strip_prefix("r=").unwrap();
+        let salt = BASE64.decode(attributes[1].strip_prefix("s=").unwrap()).unwrap();
+        let iterations = attributes[2].strip_prefix("i=").unwrap().parse().unwrap();
+
+        let salted_password = salt_password(password, &salt, iterations);
+        let without_proof = format!("c=biws,r={}", nonce);
+        let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+        let client_key = hmac_sha256(&salted_password, b"Client Key");
+        let client_signature = hmac_sha256(&Sha256::digest(&client_key), auth_message.as_bytes());
+        let proof: Vec<u8> = client_key.iter().zip(&client_signature).map(|(k, s)| k ^ s).collect();
+        let server_signature = hmac_sha256(&hmac_sha256(&salted_password, b"Server Key"), auth_message.as_bytes());
+
+        (format!("{},p={}", without_proof, BASE64.encode(proof)), format!("v={}", BASE64.encode(server_signature)))
+    }
+
+    // the example exchange from RFC 7677
+    fn rfc7677_users() -> Arc<PasswordFile> {
+        let salt = BASE64.decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let salted_password = salt_password("pencil", &salt, 4096);
+        let line = format!("user:4096:W22ZaJ0SNY7soEsUEjb6gQ==:{}", BASE64.encode(salted_password));
+        Arc::new(PasswordFile::parse(&line).unwrap())
+    }
+
+    #[test]
+    fn test_scram_rfc7677() {
+        let mut exchange = ScramExchange::new(rfc7677_users(), "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0".to_string(), [0; 32]);
+
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        assert_eq!(exchange.step(b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO"), AuthStep::Continue(server_first.as_bytes().to_vec()));
+
+        let (client_final, server_final) = scram_client_final("pencil", "n=user,r=rOprNGfwEbeRWgbNEkqO", server_first);
+        assert_eq!(client_final, "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=");
+        assert_eq!(server_final, "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=");
+        assert_eq!(
+            exchange.step(client_final.as_bytes()),
+            AuthStep::Success { user_name: "user".to_string(), data: Some(server_final.into_bytes()) }
+        );
+
+        // the exchange is over once it succeeds
+        assert_eq!(exchange.step(b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO"), AuthStep::Failure);
+    }
+
+    #[test]
+    fn test_scram_refuses_bad_proofs() {
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        let client_first = "n,,n=user,r=rOprNGfwEbeRWgbNEkqO";
+        let new_exchange = || {
+            let mut exchange = ScramExchange::new(rfc7677_users(), "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0".to_string(), [0; 32]);
+            exchange.step(client_first.as_bytes());
+            exchange
+        };
+
+        // wrong password
+        let (client_final, _) = scram_client_final("pen", &client_first[3..], server_first);
+        assert_eq!(new_exchange().step(client_final.as_bytes()), AuthStep::Failure);
+
+        // right proof, but the nonce was changed
+        let (client_final, _) = scram_client_final("pencil", &client_first[3..], server_first);
+        let tampered = client_final.replace("$k0,", "$k1,");
+        assert_eq!(new_exchange().step(tampered.as_bytes()), AuthStep::Failure);
+
+        // unknown users get a made-up salt that doesn't change, and fail at the proof
+        let server_first = |user: &str| {
+            let mut exchange = ScramExchange::new(rfc7677_users(), "nonce".to_string(), [7; 32]);
+            match exchange.step(format!("n,,n={},r=abc", user).as_bytes()) {
+                AuthStep::Continue(data) => (exchange, String::from_utf8(data).unwrap()),
+                step => panic!("Expected a challenge, got {:?}", step),
+            }
+        };
+        let (mut exchange, nobody_first) = server_first("nobody");
+        assert!(nobody_first.starts_with("r=abcnonce,s="));
+        assert!(nobody_first.ends_with(&format!(",i={}", DEFAULT_ITERATIONS)));
+        assert_eq!(server_first("nobody").1, nobody_first);
+        assert_ne!(server_first("somebody").1, nobody_first);
+        let (client_final, _) = scram_client_final("pencil", "n=nobody,r=abc", &nobody_first);
+        assert_eq!(exchange.step(client_final.as_bytes()), AuthStep::Failure);
+
+        // malformed messages
+        let mut exchange = ScramExchange::new(rfc7677_users(), "nonce".to_string(), [0; 32]);
+        assert_eq!(exchange.step(b"p=tls-unique,,n=user,r=abc"), AuthStep::Failure);
+    }
+
+    // a broker that knows alice and bob, by password or SCRAM-SHA-256
+    fn scram_broker() -> SocketAddr {
+        scram_broker_with_config(BrokerConfig::default())
+    }
+
+    fn scram_broker_with_config(config: BrokerConfig) -> SocketAddr {
+        let mut users = password_file();
+        users.set_password("bob", "builder", 10).unwrap();
+        let users = Arc::new(users);
+
+        let mut broker = MBroker::with_config(config);
+        broker.set_authenticator(Box::new(Arc::clone(&users)));
+        broker.add_auth_mechanism(Box::new(ScramSha256::new(users)));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener, Arc::new(Mutex::new(broker))));
+        addr
+    }
+
+    fn scram_connect_packet(client_id: &str, client_first: &str) -> ConnectPacket {
+        ConnectPacket {
+            authentication_method: Some(AuthenticationMethod("SCRAM-SHA-256".to_string())),
+            authentication_data: Some(AuthenticationData(Bytes::from(client_first.to_string()))),
+            ..connect_packet(client_id)
+        }
+    }
+
+    fn scram_auth_packet(reason_code: AuthenticateReason, data: &str) -> Packet {
+        Packet::Authenticate(AuthenticatePacket {
+            reason_code,
+            authentication_method: Some(AuthenticationMethod("SCRAM-SHA-256".to_string())),
+            authentication_data: Some(AuthenticationData(Bytes::from(data.to_string()))),
+            reason_string: None,
+            user_properties: vec![],
+        })
+    }
+
+    // read the server's challenge and answer it, returning the server-final-message to expect
+    fn answer_scram_challenge(
+        stream: &mut TcpStream,
+        decoder: &mut StreamDecoder,
+        password: &str,
+        client_first_bare: &str,
+    ) -> String {
+        let server_first = match read_packet(stream, decoder) {
+            Some(Packet::Authenticate(p)) => {
+                assert_eq!(p.reason_code, AuthenticateReason::ContinueAuthentication);
+                assert_eq!(p.authentication_method, Some(AuthenticationMethod("SCRAM-SHA-256".to_string())));
+                String::from_utf8(p.authentication_data.unwrap().0.to_vec()).unwrap()
+            },
+            other => panic!("Expected AUTH, got {:?}", other),
+        };
+        // the server adds to the client's nonce
+        let client_nonce = client_first_bare.split(",r=").nth(1).unwrap();
+        assert!(server_first.starts_with(&format!("r={}", client_nonce)));
+
+        let (client_final, server_final) = scram_client_final(password, client_first_bare, &server_first);
+        write_packet(stream, scram_auth_packet(AuthenticateReason::ContinueAuthentication, &client_final));
+        server_final
+    }
+
+    #[test]
+    fn test_scram_connect_and_reauthenticate() {
+        let addr = scram_broker();
+        let (mut stream, mut decoder) = open_client(addr);
+
+        write_packet(&mut stream, Packet::Connect(scram_connect_packet("1006", "n,,n=alice,r=fyko+d2lbbFgONRv9qkxdawL")));
+        let server_final = answer_scram_challenge(&mut stream, &mut decoder, "wonderland", "n=alice,r=fyko+d2lbbFgONRv9qkxdawL");
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::ConnectAck(p)) => {
+                assert_eq!(p.reason_code, ConnectReason::Success);
+                assert_eq!(p.authentication_method, Some(AuthenticationMethod("SCRAM-SHA-256".to_string())));
+                assert_eq!(p.authentication_data, Some(AuthenticationData(Bytes::from(server_final))));
+            },
+            other => panic!("Expected CONNACK, got {:?}", other),
+        }
+
+        // the same user again, with a fresh exchange
+        write_packet(&mut stream, scram_auth_packet(AuthenticateReason::ReAuthenticate, "n,,n=alice,r=3rfcNHYJY1ZVvWVs7j"));
+        let server_final = answer_scram_challenge(&mut stream, &mut decoder, "wonderland", "n=alice,r=3rfcNHYJY1ZVvWVs7j");
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::Authenticate(p)) => {
+                assert_eq!(p.reason_code, AuthenticateReason::Success);
+                assert_eq!(p.authentication_data, Some(AuthenticationData(Bytes::from(server_final))));
+            },
+            other => panic!("Expected AUTH, got {:?}", other),
+        }
+
+        // the connection carries on
+        write_packet(&mut stream, Packet::PingRequest);
+        assert_eq!(read_packet(&mut stream, &mut decoder), Some(Packet::PingResponse));
+
+        // re-authenticating as someone else ends it
+        write_packet(&mut stream, scram_auth_packet(AuthenticateReason::ReAuthenticate, "n,,n=bob,r=Lk5Ni9ZpYsQ2"));
+        answer_scram_challenge(&mut stream, &mut decoder, "builder", "n=bob,r=Lk5Ni9ZpYsQ2");
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::Disconnect(p)) => assert_eq!(p.reason_code, DisconnectReason::NotAuthorized),
+            other => panic!("Expected DISCONNECT, got {:?}", other),
+        }
+        assert_eq!(read_packet(&mut stream, &mut decoder), None);
+    }
+
+    #[test]
+    fn test_scram_wrong_password() {
+        let addr = scram_broker();
+        let (mut stream, mut decoder) = open_client(addr);
+
+        write_packet(&mut stream, Packet::Connect(scram_connect_packet("1007", "n,,n=alice,r=fyko+d2lbbFgONRv9qkxdawL")));
+        answer_scram_challenge(&mut stream, &mut decoder, "looking glass", "n=alice,r=fyko+d2lbbFgONRv9qkxdawL");
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::ConnectAck(p)) => assert_eq!(p.reason_code, ConnectReason::NotAuthorized),
+            other => panic!("Expected CONNACK, got {:?}", other),
+        }
+        assert_eq!(read_packet(&mut stream, &mut decoder), None);
+
+        // an unknown user is challenged all the same, and refused only at the proof
+        let (mut stream, mut decoder) = open_client(addr);
+        write_packet(&mut stream, Packet::Connect(scram_connect_packet("1007", "n,,n=mallory,r=fyko+d2lbbFgONRv9qkxdawL")));
+        answer_scram_challenge(&mut stream, &mut decoder, "looking glass", "n=mallory,r=fyko+d2lbbFgONRv9qkxdawL");
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::ConnectAck(p)) => assert_eq!(p.reason_code, ConnectReason::NotAuthorized),
+            other => panic!("Expected CONNACK, got {:?}", other),
+        }
+        assert_eq!(read_packet(&mut stream, &mut decoder), None);
+    }
+
+    #[test]
+    fn test_unknown_authentication_method() {
+        let addr = scram_broker();
+        let (mut stream, mut decoder) = open_client(addr);
+
+        let connect_p = ConnectPacket {
+            authentication_method: Some(AuthenticationMethod("KERBEROS".to_string())),
+            ..connect_packet("1008")
+        };
+        write_packet(&mut stream, Packet::Connect(connect_p));
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::ConnectAck(p)) => assert_eq!(p.reason_code, ConnectReason::BadAuthenticationMethod),
+            other => panic!("Expected CONNACK, got {:?}", other),
+        }
+        assert_eq!(read_packet(&mut stream, &mut decoder), None);
+
+        // AUTH from a client that connected with a password is a protocol error
+        let (mut stream, mut decoder) = open_client(addr);
+        let connect_p = ConnectPacket {
+            user_name: Some("alice".to_string()),
+            password: Some("wonderland".to_string()),
+            ..connect_packet("1009")
+        };
+        write_packet(&mut stream, Packet::Connect(connect_p));
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::ConnectAck(p)) => assert_eq!(p.reason_code, ConnectReason::Success),
+            other => panic!("Expected CONNACK, got {:?}", other),
+        }
+        write_packet(&mut stream, scram_auth_packet(AuthenticateReason::ReAuthenticate, "n,,n=alice,r=abc"));
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::Disconnect(p)) => assert_eq!(p.reason_code, DisconnectReason::ProtocolError),
+            other => panic!("Expected DISCONNECT, got {:?}", other),
+        }
+    }
+
+
+    // a CA with a certificate for the broker on localhost and one for a client named sensor1
+    struct TestCerts {
+        ca: rcgen::Certificate,
+        server: rcgen::Certificate,
+        server_key: KeyPair,
+        client: rcgen::Certificate,
+        client_key: KeyPair,
+    }
+
+    fn test_certs() -> TestCerts {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params.distinguished_name.push(DnType::CommonName, "mbroker test CA");
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let server_key = KeyPair::generate().unwrap();
+        let server = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&server_key, &ca, &ca_key)
+            .unwrap();
+
+        let client_key = KeyPair::generate().unwrap();
+        let mut client_params = CertificateParams::new(Vec::new()).unwrap();
+        client_params.distinguished_name.push(DnType::CommonName, "sensor1");
+        let client = client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+        TestCerts { ca, server, server_key, client, client_key }
+    }
+
+    // load the broker's TLS settings from PEM files, the way main does
+    fn tls_server_config(certs: &TestCerts, name: &str, client_ca: bool) -> Arc<rustls::ServerConfig> {
+        let dir = std::env::temp_dir().join(format!("mbroker-tls-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("cert.pem"), certs.server.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), certs.server_key.serialize_pem()).unwrap();
+        std::fs::write(dir.join("ca.pem"), certs.ca.pem()).unwrap();
+
+        let ca_path = dir.join("ca.pem");
+        let config = broker_tls::server_config(dir.join("cert.pem"), dir.join("key.pem"), client_ca.then_some(ca_path.as_path()));
+        std::fs::remove_dir_all(&dir).unwrap();
+        config.unwrap()
+    }
+
+    // one broker behind a plain listener and a TLS listener, returning both addresses
+    fn start_tls_broker(broker: MBroker, config: Arc<rustls::ServerConfig>) -> (SocketAddr, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tls_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addrs = (listener.local_addr().unwrap(), tls_listener.local_addr().unwrap());
+
+        let broker = Arc::new(Mutex::new(broker));
+        let tls_broker = Arc::clone(&broker);
+        thread::spawn(move || serve(listener, broker));
+        thread::spawn(move || serve_tls(tls_listener, tls_broker, config));
+        addrs
+    }
+
+    fn open_tls_client(
+        addr: SocketAddr,
+        certs: &TestCerts,
+        client_cert: bool,
+    ) -> (StreamOwned<ClientConnection, TcpStream>, StreamDecoder) {
+        let mut roots = RootCertStore::empty();
+        roots.add(certs.ca.der().clone()).unwrap();
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        let config = if client_cert {
+            let key = PrivateKeyDer::try_from(certs.client_key.serialize_der()).unwrap();
+            builder.with_client_auth_cert(vec![certs.client.der().clone()], key).unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+
+        let conn = ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap();
+        let (stream, decoder) = open_client(addr);
+        (StreamOwned::new(conn, stream), decoder)
+    }
+
+    #[test]
+    fn test_tls_and_tcp_clients_share_the_broker() {
+        let certs = test_certs();
+        let (addr, tls_addr) = start_tls_broker(MBroker::new(), tls_server_config(&certs, "share", false));
+
+        let (mut tls_stream, mut tls_decoder) = open_tls_client(tls_addr, &certs, false);
+        write_packet(&mut tls_stream, Packet::Connect(connect_packet("1010")));
+        match read_packet(&mut tls_stream, &mut tls_decoder) {
+            Some(Packet::ConnectAck(p)) => assert_eq!(p.reason_code, ConnectReason::Success),
+            other => panic!("Expected CONNACK, got {:?}", other),
+        }
+        write_packet(&mut tls_stream, Packet::Subscribe(subscribe_packet(1, "lab/temperature", QoS::AtMostOnce)));
+        assert!(matches!(read_packet(&mut tls_stream, &mut tls_decoder), Some(Packet::SubscribeAck(_))));
+
+        let (mut stream, mut decoder) = open_client(addr);
+        write_packet(&mut stream, Packet::Connect(connect_packet("1011")));
+        assert!(matches!(read_packet(&mut stream, &mut decoder), Some(Packet::ConnectAck(_))));
+        write_packet(&mut stream, Packet::Publish(publish_packet("lab/temperature", "21.5", QoS::AtMostOnce, None)));
+
+        match read_packet(&mut tls_stream, &mut tls_decoder) {
+            Some(Packet::Publish(p)) => {
+                assert_eq!(p.topic.to_string(), "lab/temperature");
+                assert_eq!(p.payload, Bytes::from("21.5"));
+            },
+            other => panic!("Expected PUBLISH, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_tls_client_certificate_identity() {
+        let certs = test_certs();
+        // no password needed with a certificate, and the ACL sees its CN as the user name
+        let mut broker = MBroker::new();
+        broker.set_authenticator(Box::new(password_file()));
+        broker.set_authorizer(Box::new(AclFile::parse(ACL_RULES).unwrap()));
+        let (_, tls_addr) = start_tls_broker(broker, tls_server_config(&certs, "identity", true));
+
+        let (mut stream, mut decoder) = open_tls_client(tls_addr, &certs, true);
+        write_packet(&mut stream, Packet::Connect(connect_packet("1012")));
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::ConnectAck(p)) => assert_eq!(p.reason_code, ConnectReason::Success),
+            other => panic!("Expected CONNACK, got {:?}", other),
+        }
+
+        let mut sub_p = subscribe_packet(1, "sensor1/#", QoS::AtMostOnce);
+        sub_p.subscription_topics.extend(subscribe_packet(1, "alice/#", QoS::AtMostOnce).subscription_topics);
+        write_packet(&mut stream, Packet::Subscribe(sub_p));
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::SubscribeAck(p)) => {
+                assert_eq!(p.reason_codes, vec![SubscribeAckReason::GrantedQoSZero, SubscribeAckReason::NotAuthorized]);
+            },
+            other => panic!("Expected SUBACK, got {:?}", other),
+        }
+
+        // without a certificate the handshake fails and nothing gets through
+        let (mut stream, mut decoder) = open_tls_client(tls_addr, &certs, false);
+        let _ = stream.write_all(b"\x10\x00");
+        assert_eq!(read_packet(&mut stream, &mut decoder), None);
+    }
+
+    #[test]
+    fn test_parse_tls_options() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let options = parse_options(&args(&["--tls-cert", "cert.pem", "--tls-key", "key.pem", "--tls-port", "8884"])).unwrap();
+        assert_eq!(options.tls_cert, Some("cert.pem".to_string()));
+        assert_eq!(options.tls_key, Some("key.pem".to_string()));
+        assert_eq!(options.tls_port, Some(8884));
+
+        assert!(parse_options(&args(&["--tls-cert", "cert.pem"])).is_err());
+        assert!(parse_options(&args(&["--tls-client-ca", "ca.pem"])).is_err());
+        assert!(parse_options(&args(&["--tls-cert", "cert.pem", "--tls-key", "key.pem", "--tls-port", "tls"])).is_err());
+    }
+
+
+    // one broker behind a plain listener and a WebSocket listener, returning both addresses
+    fn start_ws_broker() -> (SocketAddr, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ws_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addrs = (listener.local_addr().unwrap(), ws_listener.local_addr().unwrap());
+
+        let broker = Arc::new(Mutex::new(MBroker::new()));
+        let ws_broker = Arc::clone(&broker);
+        thread::spawn(move || serve(listener, broker));
+        thread::spawn(move || serve_ws(ws_listener, ws_broker));
+        addrs
+    }
+
+    // the WebSocket handshake as a browser would start it, offering the protocol if given
+    fn open_ws_socket(addr: SocketAddr, protocol: Option<&str>) -> Option<WebSocket<TcpStream>> {
+        let mut request = format!("ws://{}/mqtt", addr).into_client_request().unwrap();
+        if let Some(protocol) = protocol {
+            request.headers_mut().insert("Sec-WebSocket-Protocol", protocol.parse().unwrap());
+        }
+        let (stream, _) = open_client(addr);
+        let (socket, response) = tungstenite::client(request, stream).ok()?;
+        assert_eq!(response.headers()["Sec-WebSocket-Protocol"], "mqtt");
+        Some(socket)
+    }
+
+    fn open_ws_client(addr: SocketAddr) -> (WsStream, StreamDecoder) {
+        (WsStream::new(open_ws_socket(addr, Some("mqtt")).unwrap()), StreamDecoder::new())
+    }
+
+    #[test]
+    fn test_websocket_and_tcp_clients_share_the_broker() {
+        let (addr, ws_addr) = start_ws_broker();
+
+        let (mut ws_stream, mut ws_decoder) = open_ws_client(ws_addr);
+        write_packet(&mut ws_stream, Packet::Connect(connect_packet("1013")));
+        match read_packet(&mut ws_stream, &mut ws_decoder) {
+            Some(Packet::ConnectAck(p)) => assert_eq!(p.reason_code, ConnectReason::Success),
+            other => panic!("Expected CONNACK, got {:?}", other),
+        }
+        write_packet(&mut ws_stream, Packet::Subscribe(subscribe_packet(1, "dashboard/#", QoS::AtMostOnce)));
+        assert!(matches!(read_packet(&mut ws_stream, &mut ws_decoder), Some(Packet::SubscribeAck(_))));
+
+        let (mut stream, mut decoder) = open_client(addr);
+        write_packet(&mut stream, Packet::Connect(connect_packet("1014")));
+        assert!(matches!(read_packet(&mut stream, &mut decoder), Some(Packet::ConnectAck(_))));
+        write_packet(&mut stream, Packet::Subscribe(subscribe_packet(1, "devices/#", QoS::AtMostOnce)));
+        assert!(matches!(read_packet(&mut stream, &mut decoder), Some(Packet::SubscribeAck(_))));
+
+        // TCP to WebSocket
+        write_packet(&mut stream, Packet::Publish(publish_packet("dashboard/alerts", "overheat", QoS::AtMostOnce, None)));
+        match read_packet(&mut ws_stream, &mut ws_decoder) {
+            Some(Packet::Publish(p)) => {
+                assert_eq!(p.topic.to_string(), "dashboard/alerts");
+                assert_eq!(p.payload, Bytes::from("overheat"));
+            },
+            other => panic!("Expected PUBLISH, got {:?}", other),
+        }
+
+        // and back
+        write_packet(&mut ws_stream, Packet::Publish(publish_packet("devices/fan", "on", QoS::AtMostOnce, None)));
+        match read_packet(&mut stream, &mut decoder) {
+            Some(Packet::Publish(p)) => {
+                assert_eq!(p.topic.to_string(), "devices/fan");
+                assert_eq!(p.payload, Bytes::from("on"));
+            },
+            other => panic!("Expected PUBLISH, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_websocket_packets_across_messages() {
+        let (_, ws_addr) = start_ws_broker();
+        let mut socket = open_ws_socket(ws_addr, Some("mqtt")).unwrap();
+
+        // CONNECT split over two messages, then two PINGREQs in one
+        let mut buf = BytesMut::new();
+        cm_encode(Packet::Connect(connect_packet("1015")), &mut buf).unwrap();
+        let second_half = buf.split_off(buf.len() / 2);
+        socket.send(Message::Binary(buf.to_vec())).unwrap();
+        socket.send(Message::Binary(second_half.to_vec())).unwrap();
+        socket.send(Message::Binary(vec![0xC0, 0x00, 0xC0, 0x00])).unwrap();
+
+        let (mut stream, mut decoder) = (WsStream::new(socket), StreamDecoder::new());
+        assert!(matches!(read_packet(&mut stream, &mut decoder), Some(Packet::ConnectAck(_))));
+        assert_eq!(read_packet(&mut stream, &mut decoder), Some(Packet::PingResponse));
+        assert_eq!(read_packet(&mut stream, &mut decoder), Some(Packet::PingResponse));
+
+        // text messages don't carry MQTT
+        let mut socket = open_ws_socket(ws_addr, Some("mqtt")).unwrap();
+        socket.send(Message::Text("CONNECT".to_string())).unwrap();
+        let (mut stream, mut decoder) = (WsStream::new(socket), StreamDecoder::new());
+        assert_eq!(read_packet(&mut stream, &mut decoder), None);
+    }
+
+    #[test]
+    fn test_websocket_needs_mqtt_subprotocol() {
+        let (_, ws_addr) = start_ws_broker();
+        assert!(open_ws_socket(ws_addr, None).is_none());
+        assert!(open_ws_socket(ws_addr, Some("wamp")).is_none());
+        // browsers may offer several
+        assert!(open_ws_socket(ws_addr, Some("mqttv3.1,mqtt")).is_some());
+    }
+
+    #[test]
+    fn test_parse_ws_options() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert_eq!(parse_options(&args(&["--ws-port", "8080"])).unwrap().ws_port, Some(8080));
+        assert!(parse_options(&args(&["--ws-port"])).is_err());
+        assert!(parse_options(&args(&["--ws-port", "http"])).is_err());
     }
 
 }
\ No newline at end of file
@@ -1,18 +1,85 @@
+#[allow(clippy::module_inception)]
 pub mod msg_parser {
     use bytes::{BytesMut};
     use mqtt_v5::{
         decoder, encoder,
-        types::{ProtocolVersion},
+        types::{ConnectReason, DecodeError, DisconnectReason, Packet, ProtocolVersion},
     };
+    use std::fmt;
+
+    // Errors from encoding or decoding packets
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum ParseError {
+        // not enough bytes for a whole packet yet
+        Incomplete,
+        // bytes don't form a valid packet
+        MalformedPacket,
+        // packet is well formed but breaks the protocol rules
+        ProtocolError,
+        // packet is over the size this decoder accepts
+        PacketTooLarge,
+        // client speaks a protocol version other than MQTT 5
+        UnsupportedVersion,
+        // packet couldn't be turned into bytes
+        EncodeFailed,
+    }
+
+    impl ParseError {
+        // reason code to send back in a CONNACK when the error happens during CONNECT
+        pub fn connect_reason(&self) -> ConnectReason {
+            match self {
+                ParseError::Incomplete | ParseError::MalformedPacket => ConnectReason::MalformedPacket,
+                ParseError::ProtocolError => ConnectReason::ProtocolError,
+                ParseError::PacketTooLarge => ConnectReason::PacketTooLarge,
+                ParseError::UnsupportedVersion => ConnectReason::UnsupportedProtocolVersion,
+                ParseError::EncodeFailed => ConnectReason::ImplementationSpecificError,
+            }
+        }
+
+        // reason code to send back in a DISCONNECT once the connection is up
+        pub fn disconnect_reason(&self) -> DisconnectReason {
+            match self {
+                ParseError::Incomplete | ParseError::MalformedPacket => DisconnectReason::MalformedPacket,
+                ParseError::ProtocolError | ParseError::UnsupportedVersion => DisconnectReason::ProtocolError,
+                ParseError::PacketTooLarge => DisconnectReason::PacketTooLarge,
+                ParseError::EncodeFailed => DisconnectReason::ImplementationSpecificError,
+            }
+        }
+    }
+
+    impl fmt::Display for ParseError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let msg = match self {
+                ParseError::Incomplete => "Packet is incomplete",
+                ParseError::MalformedPacket => "Packet is malformed",
+                ParseError::ProtocolError => "Packet violates the protocol",
+                ParseError::PacketTooLarge => "Packet is too large",
+                ParseError::UnsupportedVersion => "Protocol version is not supported",
+                ParseError::EncodeFailed => "Packet wasn't encoded",
+            };
+            write!(f, "{}", msg)
+        }
+    }
+
+    impl From<DecodeError> for ParseError {
+        fn from(err: DecodeError) -> Self {
+            match err {
+                DecodeError::InvalidProtocolVersion => ParseError::UnsupportedVersion,
+                DecodeError::PacketTooLarge => ParseError::PacketTooLarge,
+                DecodeError::InvalidPropertyForPacket => ParseError::ProtocolError,
+                _ => ParseError::MalformedPacket,
+            }
+        }
+    }
 
     #[allow(dead_code)]
     pub fn cm_encode(
         packet: mqtt_v5::types::Packet,
         buffer: &mut BytesMut,
-    ) -> Result<&mut BytesMut, String> {
+    ) -> Result<&mut BytesMut, ParseError> {
         encoder::encode_mqtt(&packet, buffer, ProtocolVersion::V500);
         if buffer.is_empty() {
-            Err("Packet wasn't encoded".to_string())
+            Err(ParseError::EncodeFailed)
         } else {
             Ok(buffer)
         }
@@ -21,14 +88,94 @@ pub mod msg_parser {
     // Decode function
     //  input: bytes of encoded packet
     //  output: packet
-    pub fn cm_decode(buffer: & [u8]) -> Result<mqtt_v5::types::Packet, String> {
-        let mut b = BytesMut::from(buffer);
-        if buffer.is_empty() {
-            Err("Buffer was empty".to_string())
+    #[allow(dead_code)]
+    pub fn cm_decode(buffer: & [u8]) -> Result<mqtt_v5::types::Packet, ParseError> {
+        let mut decoder = StreamDecoder::new();
+        decoder.feed(buffer);
+        decoder.next_packet()?.ok_or(ParseError::Incomplete)
+    }
+
+    // Streaming decoder
+    //  collects bytes as they arrive from the socket and hands back
+    //  complete packets one at a time
+    #[derive(Default)]
+    pub struct StreamDecoder {
+        buffer: BytesMut,
+        max_packet_size: Option<usize>,
+    }
+
+    impl StreamDecoder {
+        pub fn new() -> Self {
+            Self { buffer: BytesMut::new(), max_packet_size: None }
+        }
+
+        // reject any packet bigger than max_packet_size bytes
+        pub fn with_max_packet_size(max_packet_size: usize) -> Self {
+            Self { buffer: BytesMut::new(), max_packet_size: Some(max_packet_size) }
+        }
+
+        // add bytes read from the socket
+        pub fn feed(&mut self, bytes: &[u8]) {
+            self.buffer.extend_from_slice(bytes);
+        }
+
+        // number of bytes waiting to be decoded
+        #[allow(dead_code)]
+        pub fn buffered(&self) -> usize {
+            self.buffer.len()
+        }
+
+        // returns the next complete packet, or None if more data is needed
+        pub fn next_packet(&mut self) -> Result<Option<Packet>, ParseError> {
+            let frame_len = match frame_length(&self.buffer)? {
+                Some(len) => len,
+                None => return Ok(None),
+            };
+
+            // no need to wait for the rest of a packet we're going to refuse
+            if self.max_packet_size.map(|max| frame_len > max).unwrap_or(false) {
+                return Err(ParseError::PacketTooLarge);
+            }
+
+            if frame_len > self.buffer.len() {
+                return Ok(None);
+            }
+
+            // decode the frame on its own so a bad packet can't eat into the next one
+            let mut frame = self.buffer.split_to(frame_len);
+
+            // a property length or id over 4 bytes overflows in mqtt-v5 0.1.1's decode_variable_int,
+            // Cargo.toml builds it with overflow checks off so that comes back as an error
+            match decoder::decode_mqtt(&mut frame, ProtocolVersion::V500)? {
+                Some(Packet::Connect(p)) if p.protocol_version != ProtocolVersion::V500 => {
+                    Err(ParseError::UnsupportedVersion)
+                },
+                Some(packet) => Ok(Some(packet)),
+                // the whole frame is here, so running out of bytes means it was malformed
+                None => Err(ParseError::MalformedPacket),
+            }
+        }
+    }
+
+    // Total length of the packet at the front of the buffer (fixed header included),
+    // or None if the fixed header hasn't fully arrived yet
+    fn frame_length(buffer: &[u8]) -> Result<Option<usize>, ParseError> {
+        let mut remaining_len: usize = 0;
+        let mut multiplier: usize = 1;
+
+        // the remaining length is a variable byte integer of at most 4 bytes
+        for (i, byte) in buffer.iter().skip(1).take(4).enumerate() {
+            remaining_len += (*byte & 0b0111_1111) as usize * multiplier;
+            if byte & 0b1000_0000 == 0 {
+                return Ok(Some(1 + (i + 1) + remaining_len));
+            }
+            multiplier *= 128;
+        }
+
+        if buffer.len() >= 5 {
+            Err(ParseError::MalformedPacket)
         } else {
-            Ok(decoder::decode_mqtt(&mut b, ProtocolVersion::V500)
-                .unwrap()
-                .unwrap())
+            Ok(None)
         }
     }
 }
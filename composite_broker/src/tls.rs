@@ -0,0 +1,72 @@
+#[allow(clippy::module_inception)]
+pub mod tls {
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use rustls::server::WebPkiClientVerifier;
+    use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+    use std::fs::File;
+    use std::io::{self, BufReader};
+    use std::net::TcpStream;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    // A client's socket with TLS over it
+    pub type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+    // Server settings from PEM files: the certificate chain, its private key,
+    // and if given the CA that client certificates must be signed by
+    pub fn server_config(
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+        client_ca_path: Option<&Path>,
+    ) -> io::Result<Arc<ServerConfig>> {
+        let certs = load_certs(cert_path)?;
+        let key = load_key(key_path)?;
+
+        let builder = ServerConfig::builder();
+        let builder = match client_ca_path {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(path)? {
+                    roots.add(cert).map_err(invalid_data)?;
+                }
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build().map_err(invalid_data)?;
+                builder.with_client_cert_verifier(verifier)
+            },
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder.with_single_cert(certs, key).map_err(invalid_data)?;
+        Ok(Arc::new(config))
+    }
+
+    // Run the handshake on a newly accepted socket
+    pub fn accept(config: Arc<ServerConfig>, mut stream: TcpStream) -> io::Result<TlsStream> {
+        let mut conn = ServerConnection::new(config).map_err(invalid_data)?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream)?;
+        }
+        Ok(StreamOwned::new(conn, stream))
+    }
+
+    // The subject CN of the certificate the client proved it holds, None without one
+    pub fn peer_common_name(conn: &ServerConnection) -> Option<String> {
+        let cert = conn.peer_certificates()?.first()?;
+        let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+        let common_name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+        Some(common_name.to_string())
+    }
+
+    fn load_certs(path: impl AsRef<Path>) -> io::Result<Vec<CertificateDer<'static>>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        rustls_pemfile::certs(&mut reader).collect()
+    }
+
+    fn load_key(path: impl AsRef<Path>) -> io::Result<PrivateKeyDer<'static>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        rustls_pemfile::private_key(&mut reader)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No private key in the key file"))
+    }
+
+    fn invalid_data(error: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
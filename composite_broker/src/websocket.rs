@@ -0,0 +1,109 @@
+#[allow(clippy::module_inception)]
+pub mod websocket {
+    use std::io::{self, Read, Write};
+    use std::net::{Shutdown, TcpStream};
+    use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+    use tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+    use tungstenite::http::{HeaderValue, StatusCode};
+    use tungstenite::{Error, Message, WebSocket};
+
+    // MQTT bytes carried in WebSocket binary messages
+    // a packet may span messages and a message may hold several packets, like reads from TCP
+    pub struct WsStream {
+        socket: WebSocket<TcpStream>,
+        // the binary message being read, and how much of it has been handed out
+        message: Vec<u8>,
+        read: usize,
+    }
+
+    impl WsStream {
+        pub fn new(socket: WebSocket<TcpStream>) -> Self {
+            Self { socket, message: Vec::new(), read: 0 }
+        }
+
+        pub fn socket(&self) -> &TcpStream {
+            self.socket.get_ref()
+        }
+
+        // send a close frame and close the socket
+        pub fn shutdown(&mut self) {
+            let _ = self.socket.close(None);
+            let _ = self.socket.flush();
+            let _ = self.socket.get_ref().shutdown(Shutdown::Both);
+        }
+    }
+
+    impl Read for WsStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            // hand out the rest of the last message before reading another
+            while self.read == self.message.len() {
+                match self.socket.read() {
+                    Ok(Message::Binary(data)) => {
+                        self.message = data;
+                        self.read = 0;
+                    },
+                    // the socket answers pings itself
+                    Ok(Message::Ping(_)) | Ok(Message::Pong(_)) | Ok(Message::Frame(_)) => {},
+                    Ok(Message::Text(_)) => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "MQTT over WebSocket needs binary messages"))
+                    },
+                    Ok(Message::Close(_)) | Err(Error::ConnectionClosed) | Err(Error::AlreadyClosed) => return Ok(0),
+                    Err(e) => return Err(io_error(e)),
+                }
+            }
+
+            let len = buf.len().min(self.message.len() - self.read);
+            buf[..len].copy_from_slice(&self.message[self.read..self.read + len]);
+            self.read += len;
+            Ok(len)
+        }
+    }
+
+    impl Write for WsStream {
+        // every write goes out right away as one binary message
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.socket.send(Message::Binary(buf.to_vec())).map_err(io_error)?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.socket.flush().map_err(io_error)
+        }
+    }
+
+    // Run the WebSocket handshake on a newly accepted socket
+    pub fn accept(stream: TcpStream) -> io::Result<WsStream> {
+        let socket = tungstenite::accept_hdr(stream, choose_mqtt_protocol)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        Ok(WsStream::new(socket))
+    }
+
+    // the client has to offer the mqtt subprotocol, and the broker names it in the response
+    // tungstenite's handshake callback decides the error type
+    #[allow(clippy::result_large_err)]
+    fn choose_mqtt_protocol(request: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
+        let offered = request
+            .headers()
+            .get_all(SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|protocol| protocol.trim() == "mqtt");
+
+        if !offered {
+            let mut error = ErrorResponse::new(Some("The mqtt subprotocol is required".to_string()));
+            *error.status_mut() = StatusCode::BAD_REQUEST;
+            return Err(error);
+        }
+        response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("mqtt"));
+        Ok(response)
+    }
+
+    // keep io errors as they are, so read timeouts still look like timeouts
+    fn io_error(error: Error) -> io::Error {
+        match error {
+            Error::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}